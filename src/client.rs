@@ -1,20 +1,73 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use bytes::Bytes;
 use tokio::sync::mpsc::Receiver;
+use log::debug;
+use tokio::time::{sleep, Instant};
 use url::Url;
 
-use crate::connection::{Connection, ConnectionOptions};
+use crate::cookie::CookieJar;
+use crate::connection::{Connection, ConnectionOptions, Endpoint};
 use crate::header::HttpHeader;
 use crate::request::ReadyRequest;
-use anyhow::Result;
-use crate::error::MyError;
+use crate::response_reader::Informational;
+use crate::tls::{Handshake, TlsConnector};
+use crate::redirect::Redirect;
+use anyhow::{anyhow, Error, Result};
+use crate::error::MyError::TooManyRedirects;
 
 #[derive(Debug)]
 pub struct Response {
     pub status: u32,
     pub headers: Vec<HttpHeader>,
-    pub body_reader: Option<Receiver<Bytes>>
+    /// Interim responses, such as `103 Early Hints`, received before this one.
+    pub informational: Vec<Informational>,
+    pub body_reader: Option<Receiver<Result<Bytes>>>,
+    /// Set on the first response of a freshly opened TLS connection.
+    pub handshake: Option<Handshake>,
+    /// Attempts that failed or were answered with a retryable status before this response.
+    pub retries: usize,
+    /// Redirects followed to get this response, in order.
+    pub redirects: Vec<Redirect>,
+    /// Updated while the body is read, final once the body reader is closed.
+    pub body_stats: Arc<BodyStats>,
+    /// Trailer fields of a chunked body, complete once the body reader is closed.
+    pub trailers: Arc<Mutex<Vec<HttpHeader>>>
+}
+
+/// Body sizes of a response: as received with the transfer coding removed,
+/// and after decoding its content coding.
+#[derive(Debug, Default)]
+pub struct BodyStats {
+    pub(crate) received: AtomicUsize,
+    pub(crate) decoded: AtomicUsize
+}
+
+impl BodyStats {
+    pub fn received(&self) -> usize {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn decoded(&self) -> usize {
+        self.decoded.load(Ordering::Relaxed)
+    }
+}
+
+/// Context of the error of a request that was retried before it failed, counted
+/// like `Response::retries`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Retries(pub usize);
+
+impl Display for Retries {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed after {} retries", self.0)
+    }
+}
+
+fn with_retries(error: Error, retries: usize) -> Error {
+    if retries == 0 { error } else { error.context(Retries(retries)) }
 }
 
 impl Default for Response {
@@ -22,63 +75,162 @@ impl Default for Response {
         Response {
             status: 0,
             headers: Vec::with_capacity(20),
-            body_reader: None
+            informational: Vec::new(),
+            body_reader: None,
+            handshake: None,
+            retries: 0,
+            redirects: Vec::new(),
+            body_stats: Arc::new(BodyStats::default()),
+            trailers: Arc::new(Mutex::new(Vec::new()))
         }
     }
 }
 
 pub struct HttpClient {
-    connections: HashMap<String, Connection>
+    options: ConnectionOptions,
+    tls: TlsConnector,
+    connections: HashMap<String, Connection>,
+    cookies: Option<CookieJar>
 }
 
 impl HttpClient {
-    pub async fn new() -> HttpClient {
-        HttpClient { connections: HashMap::new() }
+    pub async fn new() -> Result<HttpClient> {
+        HttpClient::with_options(ConnectionOptions::default()).await
+    }
+
+    pub async fn with_options(options: ConnectionOptions) -> Result<HttpClient> {
+        let tls = TlsConnector::new(&options.tls)?;
+        let cookies = if options.cookies { Some(CookieJar::new()) } else { None };
+        Ok(HttpClient { options, tls, connections: HashMap::new(), cookies })
+    }
+
+    pub fn cookie_jar(&mut self) -> Option<&mut CookieJar> {
+        self.cookies.as_mut()
     }
 
+    async fn connect(&self, endpoint: &Endpoint, deadline: Option<Instant>) -> Result<Connection> {
+        match endpoint {
+            Endpoint::Tcp { host, port, use_tls } => {
+                let tls = if *use_tls { Some(&self.tls) } else { None };
+                Connection::new(host, *port, tls, self.options.clone(), deadline).await
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Connection::new_unix(path, self.options.clone(), deadline).await
+        }
+    }
+
+    /// Sends the request, following redirects and retrying as allowed by the
+    /// policies of the options. The request timeout covers all of it. Retries
+    /// are summed over all redirects, an error carries them as `Retries` context.
     pub async fn perform_request(
         &mut self,
         url: &Url,
         request: Arc<ReadyRequest>
     ) -> Result<Response> {
-        let scheme = url.scheme();
-        let host = url.host().expect("There must be domain").to_string();
-        let use_tls = scheme == "https";
-        let port = url.port().unwrap_or({
-            if use_tls {
-                443u16
-            } else {
-                80u16
+        let deadline = self.options.request_timeout.map(|timeout| Instant::now() + timeout);
+        let mut url = url.clone();
+        let mut request = request;
+        let mut redirects = Vec::new();
+        let mut retries = 0;
+        loop {
+            let (response, hop_retries) = self.perform_with_retries(&url, request.clone(), deadline).await;
+            retries += hop_retries;
+            let response = response.map_err(|error| with_retries(error, retries))?;
+            let policy = &self.options.redirect;
+            let Some(location) = policy.location(&url, response.status, &response.headers) else {
+                return Ok(Response { redirects, retries, ..response });
+            };
+            if redirects.len() >= policy.max_redirects {
+                return Err(with_retries(anyhow!(TooManyRedirects), retries));
+            }
+            debug!("Following redirect {} to {}", response.status, location);
+            if let Some(mut body_reader) = response.body_reader {
+                while body_reader.recv().await.is_some() {}
             }
-        });
+            request = Arc::new(policy.follow(&request, &url, response.status, &location)
+                .map_err(|error| with_retries(error, retries))?);
+            redirects.push(Redirect { status: response.status, location: location.clone() });
+            url = location;
+        }
+    }
+
+    /// Result of the last attempt and the number of retries before it.
+    async fn perform_with_retries(
+        &mut self,
+        url: &Url,
+        request: Arc<ReadyRequest>,
+        deadline: Option<Instant>
+    ) -> (Result<Response>, usize) {
+        let endpoint = match Endpoint::new(url, &self.options) {
+            Ok(endpoint) => endpoint,
+            Err(error) => return (Err(error), 0)
+        };
+        let retryable = !self.options.retry.idempotent_only || request.method.is_idempotent();
+
+        let mut attempt = 1;
+        loop {
+            let result = self.send(url, &endpoint, request.clone(), deadline).await;
+            let policy = &self.options.retry;
+            let retry = retryable && attempt < policy.max_attempts && match &result {
+                Ok(response) => policy.retries_status(response.status),
+                Err(error) => policy.retries_error(error)
+            };
+            let backoff = policy.backoff(attempt);
+            if !retry || deadline.is_some_and(|deadline| Instant::now() + backoff >= deadline) {
+                return (result, attempt - 1);
+            }
+            match result {
+                Ok(response) => {
+                    debug!("Retrying after status {}, attempt {}", response.status, attempt);
+                    // Drain the body so that the connection can be reused
+                    if let Some(mut body_reader) = response.body_reader {
+                        while body_reader.recv().await.is_some() {}
+                    }
+                }
+                Err(error) => debug!("Retrying after error {}, attempt {}", error, attempt)
+            }
+            sleep(backoff).await;
+            attempt += 1;
+        }
+    }
 
-        let url = format!("{}:{}", host, port);
-        let url = url.as_str();
+    async fn send(
+        &mut self,
+        url: &Url,
+        endpoint: &Endpoint,
+        request: Arc<ReadyRequest>,
+        deadline: Option<Instant>
+    ) -> Result<Response> {
+        let request = match self.cookies.as_mut().and_then(|jar| jar.header_value(url)) {
+            Some(cookies) => Arc::new(request.with_cookies(url, &cookies)?),
+            None => request
+        };
+        let key = endpoint.key();
+        let key = key.as_str();
 
-        let connection = if let Some(connection) = self.connections.get_mut(url) {
+        if !self.options.reuse.keep_alive
+            || self.connections.get(key).is_some_and(|connection| !connection.is_reusable()) {
+            self.connections.remove(key);
+        }
+
+        let connection = if let Some(connection) = self.connections.get_mut(key) {
             connection
         } else {
-            self.connections.insert(
-                url.to_string(),
-                Connection::new(host.as_str(), port, use_tls, ConnectionOptions::default()).await?
-            );
-            self.connections.get_mut(url).expect("Invalid state")
+            self.connections.insert(key.to_string(), self.connect(endpoint, deadline).await?);
+            self.connections.get_mut(key).expect("Invalid state")
         };
 
-        match connection.send_request(request.clone()).await {
-            Err(error) => {
-                match error.downcast_ref::<MyError>() {
-                    Some(MyError::ConnectionClosedUnexpectedly) => {
-                        self.connections.insert(
-                            url.to_string(),
-                            Connection::new(host.as_str(), port, use_tls, ConnectionOptions::default()).await?
-                        );
-                        self.connections.get_mut(url).unwrap().send_request(request).await
-                    }
-                    _ => Err(error)
+        let result = connection.send_request(request, deadline).await;
+        match &result {
+            Ok(response) => {
+                if let Some(jar) = self.cookies.as_mut() {
+                    jar.store(url, &response.headers);
                 }
-            },
-            Ok(response) => Ok(response)
+            }
+            Err(_) => {
+                self.connections.remove(key);
+            }
         }
+        result
     }
 }
\ No newline at end of file
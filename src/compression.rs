@@ -0,0 +1,225 @@
+use std::io::{self, Write};
+use std::mem;
+use bytes::Bytes;
+use flate2::Compression;
+use flate2::write::{DeflateDecoder, GzDecoder, GzEncoder, ZlibDecoder, ZlibEncoder};
+use log::warn;
+use serde::Deserialize;
+use strum_macros::Display;
+
+use anyhow::Result;
+use crate::header::HttpHeader;
+use crate::utils::NEWLINE;
+
+/// Content codings (RFC 9110, 8.4.1) the client can decode and compress request bodies with.
+#[derive(Clone, Copy, Debug, Display, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[strum(serialize = "gzip")]
+    Gzip,
+    #[strum(serialize = "deflate")]
+    Deflate,
+    #[strum(serialize = "br")]
+    Br,
+    #[strum(serialize = "zstd")]
+    Zstd
+}
+
+impl Encoding {
+    fn from_token(token: &str) -> Option<Encoding> {
+        match token.to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            "br" => Some(Encoding::Br),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None
+        }
+    }
+}
+
+/// `Accept-Encoding` header line, including the trailing newline.
+pub(crate) fn accept_encoding(encodings: &[Encoding]) -> Option<Bytes> {
+    if encodings.is_empty() {
+        return None;
+    }
+    let tokens: Vec<String> = encodings.iter().map(|encoding| encoding.to_string()).collect();
+    Some(Bytes::from(format!("Accept-Encoding: {}{}", tokens.join(", "), NEWLINE)))
+}
+
+/// Compresses a request body.
+pub(crate) fn compress(encoding: Encoding, data: &[u8]) -> Result<Vec<u8>> {
+    Ok(match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+        Encoding::Br => {
+            let mut compressed = Vec::new();
+            brotli::BrotliCompress(&mut &data[..], &mut compressed, &brotli::enc::BrotliEncoderParams::default())?;
+            compressed
+        }
+        Encoding::Zstd => zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL)?
+    })
+}
+
+enum Decoder {
+    Gzip(GzDecoder<Vec<u8>>),
+    Zlib(ZlibDecoder<Vec<u8>>),
+    /// Raw deflate, sent by servers that ignore the zlib wrapper of the `deflate` coding.
+    RawDeflate(DeflateDecoder<Vec<u8>>),
+    /// `deflate` before the first bytes tell which of the two framings is used.
+    Deflate,
+    Brotli(Box<brotli::DecompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>)
+}
+
+impl Decoder {
+    fn new(encoding: Encoding) -> io::Result<Decoder> {
+        Ok(match encoding {
+            Encoding::Gzip => Decoder::Gzip(GzDecoder::new(Vec::new())),
+            Encoding::Deflate => Decoder::Deflate,
+            Encoding::Br => Decoder::Brotli(Box::new(brotli::DecompressorWriter::new(Vec::new(), 4096))),
+            Encoding::Zstd => Decoder::Zstd(zstd::stream::write::Decoder::new(Vec::new())?)
+        })
+    }
+
+    fn decode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if let Decoder::Deflate = self {
+            if data.is_empty() {
+                return Ok(Vec::new());
+            }
+            // A zlib header has compression method 8 and is a multiple of 31
+            let zlib = data[0] & 0x0f == 8 && data.get(1).is_none_or(|flags| (u16::from(data[0]) << 8 | u16::from(*flags)) % 31 == 0);
+            *self = if zlib {
+                Decoder::Zlib(ZlibDecoder::new(Vec::new()))
+            } else {
+                Decoder::RawDeflate(DeflateDecoder::new(Vec::new()))
+            };
+        }
+        match self {
+            Decoder::Gzip(decoder) => Decoder::drain(decoder, data, |decoder| decoder.get_mut()),
+            Decoder::Zlib(decoder) => Decoder::drain(decoder, data, |decoder| decoder.get_mut()),
+            Decoder::RawDeflate(decoder) => Decoder::drain(decoder, data, |decoder| decoder.get_mut()),
+            Decoder::Brotli(decoder) => Decoder::drain(decoder.as_mut(), data, |decoder| decoder.get_mut()),
+            Decoder::Zstd(decoder) => Decoder::drain(decoder, data, |decoder| decoder.get_mut()),
+            Decoder::Deflate => unreachable!()
+        }
+    }
+
+    fn drain<W: Write>(decoder: &mut W, data: &[u8], output: fn(&mut W) -> &mut Vec<u8>) -> io::Result<Vec<u8>> {
+        decoder.write_all(data)?;
+        decoder.flush()?;
+        Ok(mem::take(output(decoder)))
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.finish(),
+            Decoder::Zlib(decoder) => decoder.finish(),
+            Decoder::RawDeflate(decoder) => decoder.finish(),
+            Decoder::Deflate => Ok(Vec::new()),
+            Decoder::Brotli(decoder) => decoder.into_inner()
+                .map_err(|_| io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated brotli stream")),
+            Decoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+        }
+    }
+}
+
+/// Streaming decoder for the `Content-Encoding` of a response body.
+pub(crate) struct BodyDecoder {
+    /// In the order the codings were applied, decoding runs backwards.
+    decoders: Vec<Decoder>
+}
+
+impl BodyDecoder {
+    /// Decoder for the response, `None` if the body is not encoded or uses
+    /// an unknown coding, in which case it is passed on as it is.
+    pub(crate) fn new(headers: &[HttpHeader]) -> Result<Option<BodyDecoder>> {
+        let tokens = headers.iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("Content-Encoding"))
+            .flat_map(|header| header.value.split(','))
+            .map(str::trim)
+            .filter(|token| !token.is_empty() && !token.eq_ignore_ascii_case("identity"));
+        let mut decoders = Vec::new();
+        for token in tokens {
+            match Encoding::from_token(token) {
+                Some(encoding) => decoders.push(Decoder::new(encoding)?),
+                None => {
+                    warn!("Unsupported content encoding {}, body is not decoded", token);
+                    return Ok(None);
+                }
+            }
+        }
+        Ok(if decoders.is_empty() { None } else { Some(BodyDecoder { decoders }) })
+    }
+
+    pub(crate) fn decode(&mut self, chunk: &[u8]) -> Result<Bytes> {
+        let mut data = chunk.to_vec();
+        for decoder in self.decoders.iter_mut().rev() {
+            data = decoder.decode(&data)?;
+        }
+        Ok(Bytes::from(data))
+    }
+
+    /// Checks that every stream is complete and returns what was still buffered.
+    pub(crate) fn finish(self) -> Result<Bytes> {
+        let mut data = Vec::new();
+        for mut decoder in self.decoders.into_iter().rev() {
+            let mut decoded = decoder.decode(&data)?;
+            decoded.extend(decoder.finish()?);
+            data = decoded;
+        }
+        Ok(Bytes::from(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::DeflateEncoder;
+    use anyhow::Result;
+    use crate::compression::{compress, BodyDecoder, Encoding};
+    use crate::header::HttpHeader;
+
+    fn decode(content_encoding: &str, encoded: &[u8]) -> Result<Vec<u8>> {
+        let headers = [HttpHeader { name: String::from("Content-Encoding"), value: String::from(content_encoding) }];
+        let mut decoder = BodyDecoder::new(&headers)?.expect("Decoder expected");
+        let mut decoded = Vec::new();
+        for chunk in encoded.chunks(7) {
+            decoded.extend_from_slice(&decoder.decode(chunk)?);
+        }
+        decoded.extend_from_slice(&decoder.finish()?);
+        Ok(decoded)
+    }
+
+    #[test]
+    fn test_decode() -> Result<()> {
+        let body = b"Hello, compressed world! Hello, compressed world!".repeat(20);
+
+        let gzip = compress(Encoding::Gzip, &body)?;
+        assert_eq!(decode("gzip", &gzip)?, body);
+        assert_eq!(decode("deflate", &compress(Encoding::Deflate, &body)?)?, body);
+        assert_eq!(decode("br", &compress(Encoding::Br, &body)?)?, body);
+
+        let mut raw_deflate = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw_deflate.write_all(&body)?;
+        assert_eq!(decode("deflate", &raw_deflate.finish()?)?, body);
+
+        let zstd = compress(Encoding::Zstd, &body)?;
+        assert_eq!(decode("zstd", &zstd)?, body);
+        assert_eq!(decode("zstd, gzip", &compress(Encoding::Gzip, &zstd)?)?, body);
+
+        assert!(decode("gzip", &gzip[..gzip.len() - 4]).is_err());
+        Ok(())
+    }
+}
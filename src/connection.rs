@@ -1,95 +1,372 @@
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::path::Path;
+use bytes::Bytes;
+use futures::stream::{FuturesUnordered, StreamExt};
 use log::{debug, warn};
+use serde::Deserialize;
+use socket2::{SockRef, TcpKeepalive};
+use url::{Host, Url};
 use tokio::io::{self, AsyncRead, AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
 use tokio::net::{TcpSocket, TcpStream as TokioTcpStream};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::time::{sleep, Instant};
 use tokio::sync::Mutex;
-use tokio_native_tls::{TlsConnector, TlsStream as TokioTlsStream};
 
 use crate::client::Response;
-use crate::request::ReadyRequest;
+use crate::header::HeaderLimits;
+use crate::request::{Method, Payload, ReadyRequest};
 use crate::response_reader::{HttpEntity, HttpResponseReader};
-use crate::utils::{ip_resolve, NEWLINE_BYTES};
-use crate::constants::IDLE_TIMEOUT;
+use crate::proxy::{Proxy, ProxyKind, ProxyOptions};
+use crate::compression::{accept_encoding, BodyDecoder, Encoding};
+use crate::redirect::RedirectPolicy;
+use crate::retry::RetryPolicy;
+use crate::socks::{self, SocksTarget};
+use crate::tls::{Handshake, TlsConnector, TlsOptions, TlsStream};
+use crate::utils::{header_has_token, keep_alive_max, next_local_address, next_local_port, order_addresses, unix_socket_target, with_deadline};
+use crate::dns;
+use crate::constants::{HAPPY_EYEBALLS_DELAY, READ_BUFFER_SIZE, READ_TIMEOUT};
 use crate::measure_time;
-use anyhow::{Result, Error, anyhow};
+use anyhow::{Result, anyhow};
 use crate::error::MyError;
 use crate::error::MyError::ConnectionClosedUnexpectedly;
 
+const CONNECTION_CLOSE: &[u8] = b"Connection: close\r\n";
+
 type ResponseReader<T> = Arc<Mutex<HttpResponseReader<BufReader<ReadHalf<T>>>>>;
 
 enum StreamWriter {
     Plain(WriteHalf<TokioTcpStream>),
-    Tls(WriteHalf<TokioTlsStream<TokioTcpStream>>)
+    Tls(WriteHalf<TlsStream<TokioTcpStream>>),
+    #[cfg(unix)]
+    Unix(WriteHalf<UnixStream>)
 }
 
 enum StreamReader {
     Plain(ResponseReader<TokioTcpStream>),
-    Tls(ResponseReader<TokioTlsStream<TokioTcpStream>>)
+    Tls(ResponseReader<TlsStream<TokioTcpStream>>),
+    #[cfg(unix)]
+    Unix(ResponseReader<UnixStream>)
+}
+
+/// Where connections for a URL go.
+pub(crate) enum Endpoint {
+    Tcp { host: String, port: u16, use_tls: bool },
+    #[cfg(unix)]
+    Unix(PathBuf)
 }
 
-#[derive(Clone, Copy)]
+impl Endpoint {
+    pub(crate) fn new(url: &Url, options: &ConnectionOptions) -> Result<Endpoint> {
+        #[cfg(unix)]
+        if let Some((socket, _)) = unix_socket_target(url) {
+            return Ok(Endpoint::Unix(PathBuf::from(socket)));
+        }
+        #[cfg(unix)]
+        if let Some(socket) = &options.unix_socket {
+            return Ok(Endpoint::Unix(socket.clone()));
+        }
+        #[cfg(not(unix))]
+        if unix_socket_target(url).is_some() || options.unix_socket.is_some() {
+            return Err(anyhow!("Unix domain sockets are not supported on this platform"));
+        }
+        let host = match url.host().ok_or_else(|| anyhow!("URL {} has no host", url))? {
+            Host::Ipv6(addr) => addr.to_string(),
+            host => host.to_string()
+        };
+        let use_tls = url.scheme() == "https";
+        let port = url.port().unwrap_or(if use_tls { 443 } else { 80 });
+        Ok(Endpoint::Tcp { host, port, use_tls })
+    }
+
+    /// Connections are pooled by this key.
+    pub(crate) fn key(&self) -> String {
+        match self {
+            Endpoint::Tcp { host, port, .. } => format!("{}:{}", host, port),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => format!("unix:{}", path.display())
+        }
+    }
+}
+
+/// Address families a host name may resolve to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpPreference {
+    V4,
+    V6,
+    #[default]
+    Any
+}
+
+/// Which of the resolved addresses a new connection tries first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressSelection {
+    #[default]
+    First,
+    RoundRobin,
+    Random
+}
+
+/// Low level TCP settings applied before connecting.
+#[derive(Clone, Debug)]
+pub struct SocketOptions {
+    /// Disables Nagle's algorithm (`TCP_NODELAY`).
+    pub nodelay: bool,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    /// `SO_LINGER` in whole seconds, as the socket option counts them: close blocks
+    /// until unsent data is delivered or the time is up.
+    pub linger_secs: Option<u64>,
+    /// Resets the connection on close (`SO_LINGER` 0) instead of entering TIME_WAIT.
+    pub reset_on_close: bool,
+    pub keepalive: bool,
+    pub keepalive_time: Option<Duration>,
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_retries: Option<u32>,
+    /// Capacity of the buffer the response is read through.
+    pub read_buffer_size: usize
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: false,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            linger_secs: None,
+            reset_on_close: false,
+            keepalive: true,
+            keepalive_time: None,
+            keepalive_interval: None,
+            keepalive_retries: None,
+            read_buffer_size: READ_BUFFER_SIZE
+        }
+    }
+}
+
+impl SocketOptions {
+    fn apply(&self, socket: &TcpSocket) -> Result<()> {
+        let socket = SockRef::from(socket);
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        match (self.linger_secs, self.reset_on_close) {
+            (Some(_), true) => return Err(anyhow!("linger_secs and reset_on_close can't be combined")),
+            (Some(0), false) => return Err(anyhow!("linger_secs must be positive, reset_on_close resets connections")),
+            (Some(secs), false) => socket.set_linger(Some(Duration::from_secs(secs)))?,
+            (None, true) => socket.set_linger(Some(Duration::ZERO))?,
+            (None, false) => ()
+        }
+        socket.set_keepalive(self.keepalive)?;
+        if self.keepalive {
+            let mut keepalive = TcpKeepalive::new();
+            if let Some(time) = self.keepalive_time {
+                keepalive = keepalive.with_time(time);
+            }
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            if let Some(retries) = self.keepalive_retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+/// When connections are closed and replaced by new ones.
+#[derive(Clone, Debug)]
+pub struct ReuseOptions {
+    /// `false` opens a new connection for every request and asks the server to close it.
+    pub keep_alive: bool,
+    pub max_requests: Option<usize>,
+    pub max_lifetime: Option<Duration>
+}
+
+impl Default for ReuseOptions {
+    fn default() -> Self {
+        ReuseOptions { keep_alive: true, max_requests: None, max_lifetime: None }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct ConnectionOptions {
-    idle_timeout: Duration
+    pub connect_timeout: Option<Duration>,
+    pub tls_handshake_timeout: Option<Duration>,
+    /// Time between sending the request and the first byte of the response.
+    pub first_byte_timeout: Option<Duration>,
+    /// Maximum inactivity between two reads once the response started.
+    pub read_timeout: Option<Duration>,
+    /// Deadline for the whole request, from connecting until the body is read.
+    pub request_timeout: Option<Duration>,
+    pub ip_preference: IpPreference,
+    pub address_selection: AddressSelection,
+    /// Delay before racing the next address while a connection attempt is pending (Happy Eyeballs).
+    pub happy_eyeballs_delay: Duration,
+    /// Source addresses, used round-robin among those matching the target's family.
+    pub local_addresses: Vec<IpAddr>,
+    /// Inclusive range of local ports to bind, instead of an ephemeral port.
+    pub local_port_range: Option<(u16, u16)>,
+    pub socket: SocketOptions,
+    pub reuse: ReuseOptions,
+    /// Static `host:port` to address overrides, like curl's `--resolve`.
+    pub resolve: HashMap<String, Vec<IpAddr>>,
+    pub proxy: ProxyOptions,
+    /// Sends every request to this Unix domain socket instead of the URL's host.
+    pub unix_socket: Option<PathBuf>,
+    pub retry: RetryPolicy,
+    pub redirect: RedirectPolicy,
+    /// Gives the client a cookie jar, fed by `Set-Cookie` and sent back with every request.
+    pub cookies: bool,
+    /// Sent as `Accept-Encoding`, responses in one of these codings are decoded.
+    pub accept_encoding: Vec<Encoding>,
+    pub header_limits: HeaderLimits,
+    pub tls: TlsOptions
 }
 
 impl Default for ConnectionOptions {
     fn default() -> Self {
-        ConnectionOptions { idle_timeout: Duration::from_secs(IDLE_TIMEOUT) }
+        ConnectionOptions {
+            connect_timeout: None,
+            tls_handshake_timeout: None,
+            first_byte_timeout: None,
+            read_timeout: Some(Duration::from_secs(READ_TIMEOUT)),
+            request_timeout: None,
+            ip_preference: IpPreference::default(),
+            address_selection: AddressSelection::default(),
+            happy_eyeballs_delay: Duration::from_millis(HAPPY_EYEBALLS_DELAY),
+            local_addresses: Vec::new(),
+            local_port_range: None,
+            socket: SocketOptions::default(),
+            reuse: ReuseOptions::default(),
+            resolve: HashMap::new(),
+            proxy: ProxyOptions::default(),
+            unix_socket: None,
+            retry: RetryPolicy::default(),
+            redirect: RedirectPolicy::default(),
+            cookies: false,
+            accept_encoding: Vec::new(),
+            header_limits: HeaderLimits::default(),
+            tls: TlsOptions::default()
+        }
     }
 }
 
+/// Plain HTTP requests sent through a proxy use the absolute form and carry its credentials.
+pub(crate) struct ProxyForwarding {
+    authorization: Option<Bytes>
+}
+
 pub(crate) struct Connection {
     options: ConnectionOptions,
+    proxy: Option<ProxyForwarding>,
+    accept_encoding: Option<Bytes>,
     writer: StreamWriter,
     reader: StreamReader,
+    handshake: Option<Handshake>,
+    reusable: Arc<AtomicBool>,
+    created_at: Instant,
+    requests: usize,
+    /// Requests the server still accepts, from its `Keep-Alive: max=` parameter.
+    server_remaining: Option<usize>,
     pub(crate) in_progress: Arc<Mutex<bool>>
 }
 
 impl Connection {
-    pub async fn write<T: AsyncWriteExt + Unpin>(writer: &mut T, request: &Arc<ReadyRequest>, in_progress: Arc<Mutex<bool>>) -> Result<()> {
+    pub async fn write<T: AsyncWriteExt + Unpin>(
+        writer: &mut T,
+        request: &Arc<ReadyRequest>,
+        proxy: Option<&ProxyForwarding>,
+        close: bool,
+        accept_encoding: Option<&Bytes>,
+        in_progress: Arc<Mutex<bool>>
+    ) -> Result<()> {
         let mut in_progress = in_progress.lock().await;
         *in_progress = true;
-        debug!("write headers: {:?}", request.0);
-        writer.write_all(&*request.0).await?;
-        if let Some(body) = &request.1 {
-            writer.write_all(body.as_bytes()).await?
+        match proxy {
+            Some(proxy) => {
+                writer.write_all(&request.absolute_request_line).await?;
+                if let Some(authorization) = &proxy.authorization {
+                    writer.write_all(authorization).await?;
+                }
+            }
+            None => writer.write_all(&request.request_line).await?
+        }
+        if close {
+            writer.write_all(CONNECTION_CLOSE).await?;
+        }
+        if let Some(accept_encoding) = accept_encoding {
+            writer.write_all(accept_encoding).await?;
+        }
+        debug!("write headers: {:?}", request.headers);
+        writer.write_all(&request.headers).await?;
+        match &request.body {
+            Some(Payload::Bytes(body)) => writer.write_all(body).await?,
+            Some(Payload::Stream(body)) => body.write_chunked(writer).await?,
+            None => ()
         }
-        writer.write_all(NEWLINE_BYTES).await?;
-        writer.write_all(NEWLINE_BYTES).await?;
         Ok(())
     }
 
     pub async fn read<T>(
         reader: ResponseReader<T>,
         in_progress: Arc<Mutex<bool>>,
-        options: ConnectionOptions
+        reusable: Arc<AtomicBool>,
+        options: &ConnectionOptions,
+        method: &Method,
+        deadline: Option<Instant>
     ) -> Result<Response>
     where T: AsyncRead + Unpin + Send + 'static
     {
-        let mut last_packet_time = SystemTime::now();
         let mut response = Response::default();
         {
             let mut reader = reader.lock().await;
-            reader.reset();
+            reader.start(method.clone());
+            let mut timeout = (options.first_byte_timeout, MyError::FirstByteTimeout);
             loop {
-                match reader.next_entity().await {
+                let entity = with_deadline(reader.next_entity(), timeout.0, timeout.1, deadline).await;
+                timeout = (options.read_timeout, MyError::ReadTimeout);
+                match entity {
                     Ok(value) => {
-                        last_packet_time = SystemTime::now();
                         match value {
+                            HttpEntity::Informational(informational) => {
+                                response.informational.push(informational);
+                            }
                             HttpEntity::Status(status) => {
                                 response.status = status;
+                                // After 101 or a successful CONNECT the connection no longer speaks HTTP/1.1
+                                if status == 101 || (*method == Method::CONNECT && (200..300).contains(&status)) {
+                                    reusable.store(false, Ordering::Relaxed);
+                                }
                             }
                             HttpEntity::Header(header) => {
                                 response.headers.push(header);
                             }
                             HttpEntity::HeaderEnd => {
+                                if !reader.is_persistent() {
+                                    reusable.store(false, Ordering::Relaxed);
+                                }
                                 break;
                             }
                             _ => panic!("Invalid state")
                         }
                     },
                     Err(error) => {
+                        reusable.store(false, Ordering::Relaxed);
                         return match error.downcast::<MyError>() {
                             Ok(MyError::ZeroRead) => {
                                 Err(anyhow!(ConnectionClosedUnexpectedly))
@@ -98,13 +375,7 @@ impl Connection {
                                 Err(anyhow!(else_error))
                             }
                             Err(error) => {
-                                let idle = SystemTime::now().duration_since(last_packet_time)?;
-                                if idle > options.idle_timeout {
-                                    warn!("Idle timeout");
-                                    Err(anyhow!(MyError::IdleTimeout))
-                                } else {
-                                    Err(anyhow!(error))
-                                }
+                                Err(error)
                             }
                         }
                     }
@@ -117,25 +388,70 @@ impl Connection {
         let in_progress = in_progress.clone();
 
         response.body_reader = Some(receiver);
+        let stats = response.body_stats.clone();
+        let trailers = response.trailers.clone();
+        let mut decoder = if options.accept_encoding.is_empty() {
+            None
+        } else {
+            BodyDecoder::new(&response.headers)?
+        };
+        let mut decoding_failed = false;
 
         let reader = reader.clone();
+        let read_timeout = options.read_timeout;
         tokio::spawn(async move {
             let mut reader = reader.lock().await;
             loop {
-                match reader.next_entity().await {
+                match with_deadline(reader.next_entity(), read_timeout, MyError::ReadTimeout, deadline).await {
                     Ok(value) => {
                         match value {
-                            HttpEntity::Status(_) | HttpEntity::HeaderEnd | HttpEntity::Header(_) => (),
+                            HttpEntity::Informational(_) | HttpEntity::Status(_) | HttpEntity::HeaderEnd | HttpEntity::Header(_) => (),
                             HttpEntity::Body(body) => {
-                                sender.send(body).await?;
+                                stats.received.fetch_add(body.len(), Ordering::Relaxed);
+                                let body = match decoder.as_mut() {
+                                    Some(decoder) => decoder.decode(&body),
+                                    // The rest of an undecodable body is still read, but not passed on
+                                    None if decoding_failed => continue,
+                                    None => Ok(body)
+                                };
+                                match &body {
+                                    Ok(body) if body.is_empty() => continue,
+                                    Ok(body) => {
+                                        stats.decoded.fetch_add(body.len(), Ordering::Relaxed);
+                                    }
+                                    Err(_) => {
+                                        decoder = None;
+                                        decoding_failed = true;
+                                    }
+                                }
+                                // Keep draining even if nobody listens, the connection stays usable
+                                let _ = sender.send(body).await;
+                            }
+                            HttpEntity::Trailer(trailer) => {
+                                trailers.lock().expect("Trailers lock poisoned").push(trailer);
                             }
                             HttpEntity::End => {
+                                // A bodiless response, such as one to HEAD or a 304, has no stream to finish
+                                if let Some(decoder) = decoder.take().filter(|_| stats.received.load(Ordering::Relaxed) > 0) {
+                                    match decoder.finish() {
+                                        Ok(rest) if rest.is_empty() => (),
+                                        rest => {
+                                            if let Ok(rest) = &rest {
+                                                stats.decoded.fetch_add(rest.len(), Ordering::Relaxed);
+                                            }
+                                            let _ = sender.send(rest).await;
+                                        }
+                                    }
+                                }
                                 break
                             }
                         }
                     },
-                    Err(e) => {
-                        panic!("{:?}", e);
+                    Err(error) => {
+                        warn!("Failed to read body: {}", error);
+                        reusable.store(false, Ordering::Relaxed);
+                        let _ = sender.send(Err(error)).await;
+                        break;
                     }
                 };
             }
@@ -143,77 +459,238 @@ impl Connection {
             reader.reset();
             let mut in_progress = in_progress.lock().await;
             *in_progress = false;
-            Ok::<(), Error>(())
         });
 
         Ok(response)
     }
 
-    pub async fn send_request(&mut self, request: Arc<ReadyRequest>) -> Result<Response> {
+    pub(crate) fn is_reusable(&self) -> bool {
+        self.reusable.load(Ordering::Relaxed)
+            && self.server_remaining != Some(0)
+            && self.options.reuse.max_requests.is_none_or(|max| self.requests < max)
+            && self.options.reuse.max_lifetime.is_none_or(|lifetime| self.created_at.elapsed() < lifetime)
+    }
+
+    pub async fn send_request(&mut self, request: Arc<ReadyRequest>, deadline: Option<Instant>) -> Result<Response> {
         debug!("send request");
-        match &mut self.writer {
+        let reuse = &self.options.reuse;
+        let close = !reuse.keep_alive
+            || reuse.max_requests.is_some_and(|max| self.requests + 1 >= max)
+            || self.server_remaining.is_some_and(|remaining| remaining <= 1);
+        self.requests += 1;
+        self.server_remaining = self.server_remaining.map(|remaining| remaining.saturating_sub(1));
+        let written = match &mut self.writer {
             StreamWriter::Plain(writer) => {
-                Connection::write(writer, &request, self.in_progress.clone()).await?;
+                with_deadline(Connection::write(writer, &request, self.proxy.as_ref(), close, self.accept_encoding.as_ref(), self.in_progress.clone()), None, MyError::RequestTimeout, deadline).await
             },
             StreamWriter::Tls(writer) => {
-                Connection::write(writer, &request, self.in_progress.clone()).await?;
+                with_deadline(Connection::write(writer, &request, self.proxy.as_ref(), close, self.accept_encoding.as_ref(), self.in_progress.clone()), None, MyError::RequestTimeout, deadline).await
+            },
+            #[cfg(unix)]
+            StreamWriter::Unix(writer) => {
+                with_deadline(Connection::write(writer, &request, self.proxy.as_ref(), close, self.accept_encoding.as_ref(), self.in_progress.clone()), None, MyError::RequestTimeout, deadline).await
             }
+        };
+        if written.is_err() {
+            self.reusable.store(false, Ordering::Relaxed);
         }
+        written?;
         debug!("send request finished");
 
-        let response = match &self.reader {
+        let mut response = match &self.reader {
             StreamReader::Plain(reader) => {
-                Connection::read(Arc::clone(reader), self.in_progress.clone(), self.options).await?
+                Connection::read(Arc::clone(reader), self.in_progress.clone(), self.reusable.clone(), &self.options, &request.method, deadline).await?
             },
             StreamReader::Tls(reader) => {
-                Connection::read(Arc::clone(reader), self.in_progress.clone(), self.options).await?
+                Connection::read(Arc::clone(reader), self.in_progress.clone(), self.reusable.clone(), &self.options, &request.method, deadline).await?
+            },
+            #[cfg(unix)]
+            StreamReader::Unix(reader) => {
+                Connection::read(Arc::clone(reader), self.in_progress.clone(), self.reusable.clone(), &self.options, &request.method, deadline).await?
             }
         };
+        response.handshake = self.handshake.take();
+        if close || header_has_token(&response.headers, "Connection", "close") {
+            self.reusable.store(false, Ordering::Relaxed);
+        }
+        if let Some(max) = keep_alive_max(&response.headers) {
+            self.server_remaining = Some(max);
+        }
         Ok(response)
     }
 
-    pub async fn new(host: &str, port: u16, use_tls: bool, options: ConnectionOptions) -> Result<Connection> {
-        let addr_v4 = measure_time!({
-            ip_resolve(host, port)?
+    fn tcp_socket(addr: SocketAddr, options: &ConnectionOptions) -> Result<TcpSocket> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        options.socket.apply(&socket)?;
+        Ok(socket)
+    }
+
+    /// Connects from one of the configured source addresses and, if requested,
+    /// from a port of the local port range that is free for this address.
+    async fn connect_tcp(addr: SocketAddr, options: &ConnectionOptions) -> Result<TokioTcpStream> {
+        let local_ip = next_local_address(&options.local_addresses, addr.is_ipv6());
+        let Some((first, last)) = options.local_port_range else {
+            let socket = Connection::tcp_socket(addr, options)?;
+            if let Some(local_ip) = local_ip {
+                socket.bind(SocketAddr::new(local_ip, 0))?;
+            }
+            debug!("Connecting raw tcp to {}..", addr);
+            let stream = socket.connect(addr).await?;
+            debug!("TCP connected");
+            return Ok(stream);
+        };
+        let local_ip = local_ip.unwrap_or(if addr.is_ipv6() {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        });
+        for _ in first..=last {
+            let port = next_local_port(first, last);
+            let socket = Connection::tcp_socket(addr, options)?;
+            socket.set_reuseaddr(true)?;
+            match socket.bind(SocketAddr::new(local_ip, port)) {
+                Err(error) if error.kind() == ErrorKind::AddrInUse => continue,
+                result => result?
+            }
+            debug!("Connecting raw tcp to {} from port {}..", addr, port);
+            // With SO_REUSEADDR the bind succeeds even if a connection to the same address
+            // uses the port, only the connect finds out
+            match socket.connect(addr).await {
+                Err(error) if error.kind() == ErrorKind::AddrNotAvailable => continue,
+                result => {
+                    debug!("TCP connected");
+                    return Ok(result?);
+                }
+            }
+        }
+        Err(anyhow!(MyError::LocalPortsExhausted))
+    }
+
+    /// Tries the addresses in order, starting the next attempt whenever the
+    /// previous one has not finished within the Happy Eyeballs delay.
+    async fn connect_any(addrs: Vec<SocketAddr>, options: &ConnectionOptions) -> Result<TokioTcpStream> {
+        let mut pending = addrs.into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_error = anyhow!(MyError::IpResolve);
+        if let Some(addr) = pending.next() {
+            attempts.push(Connection::connect_tcp(addr, options));
+        }
+        while !attempts.is_empty() {
+            tokio::select! {
+                result = attempts.next() => match result {
+                    Some(Ok(stream)) => return Ok(stream),
+                    Some(Err(error)) => {
+                        debug!("Connection attempt failed: {}", error);
+                        last_error = error;
+                        if let Some(addr) = pending.next() {
+                            attempts.push(Connection::connect_tcp(addr, options));
+                        }
+                    }
+                    None => break
+                },
+                _ = sleep(options.happy_eyeballs_delay), if pending.len() > 0 => {
+                    if let Some(addr) = pending.next() {
+                        attempts.push(Connection::connect_tcp(addr, options));
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    pub async fn new(
+        host: &str,
+        port: u16,
+        tls: Option<&TlsConnector>,
+        options: ConnectionOptions,
+        deadline: Option<Instant>
+    ) -> Result<Connection> {
+        let proxy = options.proxy.proxy_for(tls.is_some(), host, port);
+        let (connect_host, connect_port) = match proxy {
+            Some(proxy) => (proxy.host.as_str(), proxy.port),
+            None => (host, port)
+        };
+        let addrs = measure_time!({
+            with_deadline(dns::resolve(connect_host, connect_port, &options), None, MyError::RequestTimeout, deadline).await?
         });
+        let addrs = order_addresses(addrs, options.address_selection);
+        let socks_target = match proxy {
+            Some(Proxy { kind: ProxyKind::Socks5, .. }) => {
+                let target = with_deadline(dns::resolve(host, port, &options), None, MyError::RequestTimeout, deadline).await?;
+                Some(SocksTarget::Addr(order_addresses(target, options.address_selection)[0]))
+            }
+            Some(Proxy { kind: ProxyKind::Socks5h, .. }) => Some(SocksTarget::Domain(host, port)),
+            _ => None
+        };
+        let mut handshake = None;
+        let mut forwarding = None;
         let (reader, writer) = {
-            let socket = TcpSocket::new_v4()?;
-            socket.set_keepalive(true)?;
-
-            if use_tls {
-                let native_tls_connector = native_tls::TlsConnector::builder()
-                    .danger_accept_invalid_certs(true)
-                    .danger_accept_invalid_hostnames(true)
-                    .build()?;
-                let tls_connector = TlsConnector::from(native_tls_connector);
-                debug!("Connecting raw tcp..");
-                let tcp_stream = socket.connect(addr_v4).await?;
-                debug!("TCP connected");
+            let mut tcp_stream = with_deadline(
+                Connection::connect_any(addrs, &options),
+                options.connect_timeout,
+                MyError::ConnectTimeout,
+                deadline
+            ).await?;
+
+            match (proxy, socks_target) {
+                (Some(proxy), Some(target)) => {
+                    with_deadline(
+                        socks::connect(&mut tcp_stream, target, proxy.credentials.as_ref()),
+                        options.connect_timeout,
+                        MyError::ConnectTimeout,
+                        deadline
+                    ).await?;
+                }
+                (Some(proxy), None) if tls.is_some() => {
+                    with_deadline(
+                        proxy.connect_tunnel(&mut tcp_stream, host, port),
+                        options.connect_timeout,
+                        MyError::ConnectTimeout,
+                        deadline
+                    ).await?;
+                }
+                (Some(proxy), None) => {
+                    forwarding = Some(ProxyForwarding { authorization: proxy.authorization() });
+                }
+                (None, _) => ()
+            }
+
+            if let Some(tls_connector) = tls {
                 debug!("TLS Handshaking..");
-                let (reader, writer) =
-                    io::split(tls_connector.connect(format!("{}:{}", addr_v4.ip(), addr_v4.port()).as_str(), tcp_stream).await?);
+                let tls_stream = measure_time!({
+                    with_deadline(
+                        tls_connector.connect(host, tcp_stream),
+                        options.tls_handshake_timeout,
+                        MyError::TlsHandshakeTimeout,
+                        deadline
+                    ).await?
+                });
+                handshake = Some(tls_stream.handshake());
+                debug!("TLS handshake: {:?}", handshake);
+                let (reader, writer) = io::split(tls_stream);
                 (
                     StreamReader::Tls(
                         Arc::new(
                             Mutex::new(
-                                HttpResponseReader::new(BufReader::new(reader))
+                                HttpResponseReader::with_limits(BufReader::with_capacity(options.socket.read_buffer_size, reader), options.header_limits.clone())
                             )
                         )
                     ),
                     StreamWriter::Tls(writer)
                 )
             } else {
-                debug!("Connecting raw tcp..");
-                let stream = socket.connect(addr_v4).await?;
-                debug!("TCP connected");
                 let (reader, writer) = io::split(
-                    stream
+                    tcp_stream
                 );
                 (
                     StreamReader::Plain(
                         Arc::new(
                             Mutex::new(
-                                HttpResponseReader::new(BufReader::new(reader))
+                                HttpResponseReader::with_limits(BufReader::with_capacity(options.socket.read_buffer_size, reader), options.header_limits.clone())
                             )
                         )
                     ),
@@ -222,10 +699,370 @@ impl Connection {
             }
         };
         Ok(Connection {
+            accept_encoding: accept_encoding(&options.accept_encoding),
             options,
+            proxy: forwarding,
             writer,
             reader,
+            handshake,
+            reusable: Arc::new(AtomicBool::new(true)),
+            created_at: Instant::now(),
+            requests: 0,
+            server_remaining: None,
+            in_progress: Arc::new(Mutex::new(false))
+        })
+    }
+
+    #[cfg(unix)]
+    pub async fn new_unix(path: &Path, options: ConnectionOptions, deadline: Option<Instant>) -> Result<Connection> {
+        debug!("Connecting unix socket {}..", path.display());
+        let stream = with_deadline(
+            async { Ok(UnixStream::connect(path).await?) },
+            options.connect_timeout,
+            MyError::ConnectTimeout,
+            deadline
+        ).await?;
+        let (reader, writer) = io::split(stream);
+        let reader = BufReader::with_capacity(options.socket.read_buffer_size, reader);
+        let reader = HttpResponseReader::with_limits(reader, options.header_limits.clone());
+        Ok(Connection {
+            accept_encoding: accept_encoding(&options.accept_encoding),
+            options,
+            proxy: None,
+            writer: StreamWriter::Unix(writer),
+            reader: StreamReader::Unix(Arc::new(Mutex::new(reader))),
+            handshake: None,
+            reusable: Arc::new(AtomicBool::new(true)),
+            created_at: Instant::now(),
+            requests: 0,
+            server_remaining: None,
             in_progress: Arc::new(Mutex::new(false))
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use socket2::SockRef;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpSocket};
+    use tokio::task::JoinHandle;
+    use tokio::time::{sleep, Instant};
+    #[cfg(unix)]
+    use tokio::net::UnixListener;
+    use url::Url;
+    use anyhow::Result;
+    use crate::client::{HttpClient, Response, Retries};
+    use crate::compression::Encoding;
+    use crate::connection::{Connection, ConnectionOptions, ReuseOptions, SocketOptions};
+    use crate::error::MyError;
+    use crate::redirect::RedirectPolicy;
+    use crate::request::{Method, Request};
+    use crate::retry::{RetryPolicy, StatusPattern};
+    use crate::utils::next_local_port;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("http_client_test_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await?);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await?;
+            Ok::<String, anyhow::Error>(String::from_utf8_lossy(&request).into_owned())
+        });
+
+        let url = Url::parse(&format!("unix:{}:/hello?x=1", path.display()))?;
+        let mut request = Request { method: Method::GET, url: url.clone(), headers: HashMap::new(), body: None, compress: None };
+        let mut client = HttpClient::new().await?;
+        let response = client.perform_request(&url, Arc::new(request.get_raw().await?)).await?;
+        let mut body = Vec::new();
+        let mut body_reader = response.body_reader.expect("Body reader expected");
+        while let Some(chunk) = body_reader.recv().await {
+            body.extend_from_slice(&chunk?);
+        }
+        let request = server.await??;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!((response.status, &body[..]), (200, &b"ok"[..]));
+        assert!(request.starts_with("GET /hello?x=1 HTTP/1.1\r\nHost: localhost\r\n"));
+        Ok(())
+    }
+
+    /// Answers every request on the first accepted connection with the response
+    /// and returns the requests once the client closes it.
+    async fn serve(response: &'static [u8]) -> Result<(u16, JoinHandle<Result<Vec<String>>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        Ok((port, tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut requests = Vec::new();
+            loop {
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read_u8().await {
+                        Ok(byte) => request.push(byte),
+                        Err(_) => return Ok(requests)
+                    }
+                }
+                stream.write_all(response).await?;
+                requests.push(String::from_utf8_lossy(&request).into_owned());
+            }
+        })))
+    }
+
+    /// Sends a request and returns the body, or the first error of the body reader.
+    async fn send(connection: &mut Connection, port: u16, method: Method) -> Result<Vec<u8>> {
+        let url = Url::parse(&format!("http://127.0.0.1:{}/", port))?;
+        let mut request = Request { method, url, headers: HashMap::new(), body: None, compress: None };
+        let response = connection.send_request(Arc::new(request.get_raw().await?), None).await?;
+        let mut body_reader = response.body_reader.expect("Body reader expected");
+        let mut body = Vec::new();
+        while let Some(chunk) = body_reader.recv().await {
+            body.extend_from_slice(&chunk?);
+        }
+        Ok(body)
+    }
+
+    #[tokio::test]
+    async fn test_reuse_limits() -> Result<()> {
+        let reuse = |reuse: ReuseOptions| ConnectionOptions { reuse, ..ConnectionOptions::default() };
+
+        let (port, server) = serve(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+        let mut connection = Connection::new("127.0.0.1", port, None, reuse(ReuseOptions { max_requests: Some(2), ..ReuseOptions::default() }), None).await?;
+        send(&mut connection, port, Method::GET).await?;
+        assert!(connection.is_reusable());
+        send(&mut connection, port, Method::GET).await?;
+        assert!(!connection.is_reusable());
+        drop(connection);
+        let requests = server.await??;
+        // The last allowed request asks the server to close the connection
+        assert!(!requests[0].contains("Connection: close") && requests[1].contains("Connection: close"));
+
+        let (port, server) = serve(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+        let mut connection = Connection::new("127.0.0.1", port, None, reuse(ReuseOptions { keep_alive: false, ..ReuseOptions::default() }), None).await?;
+        send(&mut connection, port, Method::GET).await?;
+        assert!(!connection.is_reusable());
+        drop(connection);
+        assert!(server.await??[0].contains("Connection: close"));
+
+        let (port, _server) = serve(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+        let lifetime = Duration::from_millis(50);
+        let mut connection = Connection::new("127.0.0.1", port, None, reuse(ReuseOptions { max_lifetime: Some(lifetime), ..ReuseOptions::default() }), None).await?;
+        send(&mut connection, port, Method::GET).await?;
+        assert!(connection.is_reusable());
+        sleep(lifetime).await;
+        assert!(!connection.is_reusable());
+
+        // The server closes the connection after its response
+        let (port, server) = serve(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n").await?;
+        let mut connection = Connection::new("127.0.0.1", port, None, ConnectionOptions::default(), None).await?;
+        send(&mut connection, port, Method::GET).await?;
+        assert!(!connection.is_reusable());
+        drop(connection);
+        assert!(!server.await??[0].contains("Connection: close"));
+
+        // The server allows one more request on the connection
+        let (port, server) = serve(b"HTTP/1.1 204 No Content\r\nKeep-Alive: timeout=5, max=1\r\n\r\n").await?;
+        let mut connection = Connection::new("127.0.0.1", port, None, ConnectionOptions::default(), None).await?;
+        send(&mut connection, port, Method::GET).await?;
+        assert!(connection.is_reusable());
+        send(&mut connection, port, Method::GET).await?;
+        assert!(!connection.is_reusable());
+        drop(connection);
+        let requests = server.await??;
+        assert!(!requests[0].contains("Connection: close") && requests[1].contains("Connection: close"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_linger() -> Result<()> {
+        let linger = |options: SocketOptions| -> Result<Option<Duration>> {
+            let socket = TcpSocket::new_v4()?;
+            options.apply(&socket)?;
+            Ok(SockRef::from(&socket).linger()?)
+        };
+        assert_eq!(linger(SocketOptions { linger_secs: Some(2), ..SocketOptions::default() })?, Some(Duration::from_secs(2)));
+        assert_eq!(linger(SocketOptions { reset_on_close: true, ..SocketOptions::default() })?, Some(Duration::ZERO));
+        assert_eq!(linger(SocketOptions::default())?, None);
+        assert!(linger(SocketOptions { linger_secs: Some(0), ..SocketOptions::default() }).is_err());
+        assert!(linger(SocketOptions { linger_secs: Some(1), reset_on_close: true, ..SocketOptions::default() }).is_err());
+        Ok(())
+    }
+
+    /// Accepts one connection, writes the response prefix after the request head
+    /// and then stalls without closing the connection.
+    async fn stall(response: &'static [u8]) -> Result<u16> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await?);
+            }
+            stream.write_all(response).await?;
+            std::future::pending::<()>().await;
+            anyhow::Ok(())
+        });
+        Ok(port)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeouts() -> Result<()> {
+        let get = |port: u16, options: ConnectionOptions| async move {
+            let url = Url::parse(&format!("http://127.0.0.1:{}/", port))?;
+            let mut request = Request { method: Method::GET, url: url.clone(), headers: HashMap::new(), body: None, compress: None };
+            let mut client = HttpClient::with_options(options).await?;
+            client.perform_request(&url, Arc::new(request.get_raw().await?)).await
+        };
+        let error = |result: Result<Response>| result.err().and_then(|error| error.downcast::<MyError>().ok());
+        let seconds = Duration::from_secs;
+
+        let start = Instant::now();
+        let options = ConnectionOptions { first_byte_timeout: Some(seconds(5)), ..ConnectionOptions::default() };
+        assert_eq!(error(get(stall(b"").await?, options).await), Some(MyError::FirstByteTimeout));
+        assert_eq!(start.elapsed().as_secs(), 5);
+
+        // Headers stalled after the first byte fall under the read timeout
+        let start = Instant::now();
+        let options = ConnectionOptions { first_byte_timeout: Some(seconds(5)), read_timeout: Some(seconds(10)), ..ConnectionOptions::default() };
+        assert_eq!(error(get(stall(b"HTTP/1.1 200 OK\r\n").await?, options).await), Some(MyError::ReadTimeout));
+        assert_eq!(start.elapsed().as_secs(), 10);
+
+        // So does the body, reported through the body reader
+        let start = Instant::now();
+        let options = ConnectionOptions { read_timeout: Some(seconds(10)), ..ConnectionOptions::default() };
+        let response = get(stall(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc").await?, options).await?;
+        let mut body_reader = response.body_reader.expect("Body reader expected");
+        assert_eq!(error(body_reader.recv().await.expect("Error expected").map(|_| Response::default())), Some(MyError::ReadTimeout));
+        assert_eq!(start.elapsed().as_secs(), 10);
+
+        // The request deadline cuts a longer phase timeout short
+        let start = Instant::now();
+        let options = ConnectionOptions { first_byte_timeout: Some(seconds(5)), request_timeout: Some(seconds(3)), ..ConnectionOptions::default() };
+        assert_eq!(error(get(stall(b"").await?, options).await), Some(MyError::RequestTimeout));
+        assert_eq!(start.elapsed().as_secs(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_ports() -> Result<()> {
+        // The cursor is shared, so only the order within the range is fixed
+        let start = next_local_port(50000, 50002);
+        let ports: Vec<u16> = (0..6).map(|_| next_local_port(50000, 50002)).collect();
+        let expected: Vec<u16> = (1..7).map(|step| 50000 + (start - 50000 + step) % 3).collect();
+        assert_eq!(ports, expected);
+        assert_eq!(next_local_port(50000, 50000), 50000);
+
+        let exhausted = |result: Result<_>| result.err().and_then(|error| error.downcast::<MyError>().ok()) == Some(MyError::LocalPortsExhausted);
+        let server = TcpListener::bind("127.0.0.1:0").await?;
+        let other_server = TcpListener::bind("127.0.0.1:0").await?;
+        let (addr, other_addr) = (server.local_addr()?, other_server.local_addr()?);
+
+        // A port held by a listener fails to bind
+        let listener = TcpListener::bind("0.0.0.0:0").await?;
+        let port = listener.local_addr()?.port();
+        let options = ConnectionOptions { local_port_range: Some((port, port)), ..ConnectionOptions::default() };
+        assert!(exhausted(Connection::connect_tcp(addr, &options).await));
+        drop(listener);
+
+        // A port held by a connection to the same address binds, but fails to connect
+        let socket = TcpSocket::new_v4()?;
+        socket.set_reuseaddr(true)?;
+        socket.bind(SocketAddr::from(([0, 0, 0, 0], port)))?;
+        let _connection = socket.connect(addr).await?;
+        assert!(exhausted(Connection::connect_tcp(addr, &options).await));
+        // The same port still reaches another address
+        let stream = Connection::connect_tcp(other_addr, &options).await?;
+        assert_eq!(stream.local_addr()?.port(), port);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_encoded_body() -> Result<()> {
+        let options = ConnectionOptions { accept_encoding: vec![Encoding::Gzip, Encoding::Br], ..ConnectionOptions::default() };
+        for (method, response) in [
+            (Method::HEAD, &b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: 20\r\n\r\n"[..]),
+            (Method::GET, b"HTTP/1.1 304 Not Modified\r\nContent-Encoding: gzip\r\n\r\n"),
+            (Method::GET, b"HTTP/1.1 204 No Content\r\nContent-Encoding: br\r\n\r\n"),
+            (Method::GET, b"HTTP/1.1 200 OK\r\nContent-Encoding: br\r\nContent-Length: 0\r\n\r\n")
+        ] {
+            let (port, _server) = serve(response).await?;
+            let mut connection = Connection::new("127.0.0.1", port, None, options.clone(), None).await?;
+            assert!(send(&mut connection, port, method).await?.is_empty());
+        }
+        Ok(())
+    }
+
+    /// Answers requests with the responses in order, on whichever connection they
+    /// arrive, and closes every connection once they are used up.
+    async fn script(responses: Vec<&'static [u8]>) -> Result<u16> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            let mut responses = responses.into_iter();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                loop {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read_u8().await {
+                            Ok(byte) => request.push(byte),
+                            Err(_) => break
+                        }
+                    }
+                    let Some(response) = responses.next().filter(|_| request.ends_with(b"\r\n\r\n")) else {
+                        break;
+                    };
+                    if stream.write_all(response).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(port)
+    }
+
+    #[tokio::test]
+    async fn test_retries_across_redirects() -> Result<()> {
+        let options = ConnectionOptions {
+            retry: RetryPolicy {
+                retry_statuses: vec![StatusPattern::Code(503)],
+                backoff_base: Duration::from_millis(1),
+                jitter: false,
+                ..RetryPolicy::default()
+            },
+            redirect: RedirectPolicy { max_redirects: 5, ..RedirectPolicy::default() },
+            ..ConnectionOptions::default()
+        };
+        let get = |port: u16| {
+            let options = options.clone();
+            async move {
+                let url = Url::parse(&format!("http://127.0.0.1:{}/a", port))?;
+                let mut request = Request { method: Method::GET, url: url.clone(), headers: HashMap::new(), body: None, compress: None };
+                let mut client = HttpClient::with_options(options).await?;
+                client.perform_request(&url, Arc::new(request.get_raw().await?)).await
+            }
+        };
+        let unavailable = &b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n"[..];
+        let redirect = &b"HTTP/1.1 302 Found\r\nLocation: /b\r\nContent-Length: 0\r\n\r\n"[..];
+
+        let response = get(script(vec![unavailable, redirect, b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"]).await?).await?;
+        assert_eq!((response.status, response.retries, response.redirects.len()), (200, 1, 1));
+
+        // Both attempts after the redirect find the connection closed
+        let error = get(script(vec![unavailable, redirect]).await?).await.expect_err("Connection closed");
+        assert_eq!(error.downcast_ref::<Retries>(), Some(&Retries(2)));
+        assert_eq!(error.downcast_ref::<MyError>(), Some(&MyError::ConnectionClosedUnexpectedly));
+        Ok(())
+    }
+}
@@ -1 +1,9 @@
-pub const IDLE_TIMEOUT: u64 = 60;
\ No newline at end of file
+pub const READ_TIMEOUT: u64 = 60;
+pub const HAPPY_EYEBALLS_DELAY: u64 = 250;
+pub const READ_BUFFER_SIZE: usize = 8 * 1024;
+pub const RETRY_BACKOFF_BASE: u64 = 50;
+pub const RETRY_BACKOFF_MAX: u64 = 2000;
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+pub const MAX_HEADER_BYTES: usize = 64 * 1024;
+pub const MAX_HEADERS: usize = 100;
+pub const TLS_SESSION_CACHE_SIZE: usize = 256;
@@ -0,0 +1,200 @@
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+use log::debug;
+use url::Url;
+
+use crate::header::HttpHeader;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// Lower case, without a leading dot.
+    pub domain: String,
+    /// Set when the cookie had no `Domain` attribute and is only sent to the exact host.
+    pub host_only: bool,
+    pub path: String,
+    /// Session cookies never expire within a run.
+    pub expires: Option<SystemTime>,
+    pub secure: bool,
+    pub http_only: bool,
+    created: u64
+}
+
+/// Cookie store of a single client (RFC 6265). Public suffixes are not checked,
+/// scenarios are expected to talk to the servers they test.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+    sequence: u64
+}
+
+impl CookieJar {
+    pub fn new() -> CookieJar {
+        CookieJar::default()
+    }
+
+    pub fn cookies(&self) -> &[Cookie] {
+        &self.cookies
+    }
+
+    pub fn clear(&mut self) {
+        self.cookies.clear();
+    }
+
+    /// Stores the cookies of the `Set-Cookie` headers of a response to the URL.
+    pub fn store(&mut self, url: &Url, headers: &[HttpHeader]) {
+        let now = SystemTime::now();
+        for header in headers.iter().filter(|header| header.name.eq_ignore_ascii_case("Set-Cookie")) {
+            let Some(cookie) = self.parse(url, &header.value, now) else {
+                debug!("Ignoring cookie {}", header.value);
+                continue;
+            };
+            let existing = self.cookies.iter().position(|other| {
+                other.name == cookie.name && other.domain == cookie.domain && other.path == cookie.path
+            });
+            let created = match existing {
+                Some(index) => self.cookies.remove(index).created,
+                None => cookie.created
+            };
+            // An expiry in the past is how servers delete cookies
+            if cookie.expires.is_none_or(|expires| expires > now) {
+                self.cookies.push(Cookie { created, ..cookie });
+            }
+        }
+    }
+
+    /// Value of the `Cookie` header for a request to the URL, if any cookie matches.
+    /// Longer paths come first, then older cookies.
+    pub fn header_value(&mut self, url: &Url) -> Option<String> {
+        let now = SystemTime::now();
+        self.cookies.retain(|cookie| cookie.expires.is_none_or(|expires| expires > now));
+        let host = url.host_str()?.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        let secure = url.scheme() == "https";
+        let mut matching: Vec<&Cookie> = self.cookies.iter()
+            .filter(|cookie| {
+                let domain_matches = if cookie.host_only { host == cookie.domain } else { domain_match(&host, &cookie.domain) };
+                domain_matches && path_match(url.path(), &cookie.path) && (secure || !cookie.secure)
+            })
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+        matching.sort_by(|a, b| b.path.len().cmp(&a.path.len()).then(a.created.cmp(&b.created)));
+        let pairs: Vec<String> = matching.iter().map(|cookie| format!("{}={}", cookie.name, cookie.value)).collect();
+        Some(pairs.join("; "))
+    }
+
+    /// Parses a `Set-Cookie` value (RFC 6265, 5.2 and 5.3).
+    fn parse(&mut self, url: &Url, value: &str, now: SystemTime) -> Option<Cookie> {
+        let host = url.host_str()?.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        let mut parts = value.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let mut domain = None;
+        let mut path = None;
+        let mut expires = None;
+        let mut max_age = None;
+        let mut secure = false;
+        let mut http_only = false;
+        for attribute in parts {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "expires" => expires = httpdate::parse_http_date(value).ok(),
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                "domain" if !value.is_empty() => domain = Some(value.trim_start_matches('.').to_ascii_lowercase()),
+                "path" if value.starts_with('/') => path = Some(value.to_owned()),
+                "secure" => secure = true,
+                "httponly" => http_only = true,
+                _ => ()
+            }
+        }
+        // Max-Age wins over Expires, zero or less expires the cookie right away
+        let expires = match max_age {
+            Some(seconds) if seconds <= 0 => Some(SystemTime::UNIX_EPOCH),
+            Some(seconds) => now.checked_add(Duration::from_secs(seconds as u64)),
+            None => expires
+        };
+        let (domain, host_only) = match domain {
+            Some(domain) if domain_match(&host, &domain) => (domain, false),
+            Some(_) => return None,
+            None => (host, true)
+        };
+        if secure && url.scheme() != "https" {
+            return None;
+        }
+        self.sequence += 1;
+        Some(Cookie {
+            name: name.to_owned(),
+            value: value.trim().to_owned(),
+            domain,
+            host_only,
+            path: path.unwrap_or_else(|| default_path(url)),
+            expires,
+            secure,
+            http_only,
+            created: self.sequence
+        })
+    }
+}
+
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host.ends_with(domain)
+            && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+            && host.parse::<IpAddr>().is_err())
+}
+
+fn path_match(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || (path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path.as_bytes()[cookie_path.len()] == b'/'))
+}
+
+/// Directory of the request path, used when the cookie has no `Path` attribute.
+fn default_path(url: &Url) -> String {
+    match url.path().rfind('/') {
+        Some(0) | None => String::from("/"),
+        Some(index) => url.path()[..index].to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use url::Url;
+    use crate::cookie::CookieJar;
+    use crate::header::HttpHeader;
+
+    fn set_cookie(value: &str) -> HttpHeader {
+        HttpHeader { name: String::from("Set-Cookie"), value: String::from(value) }
+    }
+
+    #[test]
+    fn test_jar() -> Result<()> {
+        let mut jar = CookieJar::new();
+        let login = Url::parse("https://app.example.com/account/login")?;
+        jar.store(&login, &[
+            set_cookie("session=abc; Path=/; Secure; HttpOnly"),
+            set_cookie("shared=1; Domain=.example.com; Path=/"),
+            set_cookie("local=2"),
+            set_cookie("other=3; Domain=other.com"),
+            set_cookie("gone=4; Max-Age=0")
+        ]);
+        assert_eq!(jar.cookies().len(), 3);
+        assert_eq!(
+            jar.header_value(&Url::parse("https://app.example.com/account/profile")?).as_deref(),
+            Some("local=2; session=abc; shared=1")
+        );
+        assert_eq!(jar.header_value(&Url::parse("http://api.example.com/")?).as_deref(), Some("shared=1"));
+        assert_eq!(jar.header_value(&Url::parse("https://example.org/")?), None);
+
+        jar.store(&login, &[set_cookie("session=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Path=/")]);
+        assert_eq!(jar.header_value(&Url::parse("https://app.example.com/")?).as_deref(), Some("shared=1"));
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use hickory_resolver::config::{LookupIpStrategy, ResolverConfig};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::TokioResolver;
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use tokio::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use crate::connection::{ConnectionOptions, IpPreference};
+use crate::error::MyError::IpResolve;
+
+lazy_static! {
+    static ref RESOLVER: Resolver = Resolver::new();
+}
+
+/// Non-blocking resolver shared by all connections. Answers are cached
+/// until their TTL expires.
+struct Resolver {
+    resolver: TokioResolver,
+    cache: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>
+}
+
+impl Resolver {
+    fn new() -> Self {
+        let mut builder = TokioResolver::builder_tokio().unwrap_or_else(|error| {
+            warn!("Failed to read system DNS configuration, using defaults: {}", error);
+            TokioResolver::builder_with_config(ResolverConfig::default(), TokioConnectionProvider::default())
+        });
+        // Caching is done here, where the TTL of the whole answer is known
+        builder.options_mut().cache_size = 0;
+        builder.options_mut().ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        Resolver {
+            resolver: builder.build(),
+            cache: Mutex::new(HashMap::new())
+        }
+    }
+
+    async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        if let Some((ips, valid_until)) = self.cache.lock().await.get(host) {
+            if *valid_until > Instant::now() {
+                return Ok(ips.clone());
+            }
+        }
+        let lookup = self.resolver.lookup_ip(host).await?;
+        let ips: Vec<IpAddr> = lookup.iter().collect();
+        debug!("Resolved {} to {:?}, valid until {:?}", host, ips, lookup.valid_until());
+        self.cache.lock().await.insert(host.to_owned(), (ips.clone(), lookup.valid_until()));
+        Ok(ips)
+    }
+}
+
+/// Resolves the host, honouring the static `host:port` overrides of the options.
+pub(crate) async fn resolve(host: &str, port: u16, options: &ConnectionOptions) -> Result<Vec<SocketAddr>> {
+    let ips = match options.resolve.get(&format!("{}:{}", host, port)) {
+        Some(ips) => ips.clone(),
+        None => RESOLVER.lookup(host).await?
+    };
+    let addrs: Vec<SocketAddr> = ips.into_iter()
+        .filter(|ip| match options.ip_preference {
+            IpPreference::V4 => ip.is_ipv4(),
+            IpPreference::V6 => ip.is_ipv6(),
+            IpPreference::Any => true
+        })
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect();
+    if addrs.is_empty() {
+        return Err(anyhow!(IpResolve));
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::{IpAddr, SocketAddr};
+    use anyhow::Result;
+    use crate::connection::{ConnectionOptions, IpPreference};
+    use crate::dns::resolve;
+    use crate::error::MyError;
+
+    #[tokio::test]
+    async fn test_resolve() -> Result<()> {
+        let addr = |addr: &str| addr.parse::<SocketAddr>().expect(addr);
+        let ips: Vec<IpAddr> = vec!["10.0.0.1".parse()?, "fd00::1".parse()?];
+        let options = |ip_preference: IpPreference| ConnectionOptions {
+            ip_preference,
+            resolve: HashMap::from([(String::from("api.test:443"), ips.clone())]),
+            ..ConnectionOptions::default()
+        };
+
+        // The override answers without a lookup, which would fail for this name
+        assert_eq!(resolve("api.test", 443, &options(IpPreference::Any)).await?, vec![addr("10.0.0.1:443"), addr("[fd00::1]:443")]);
+        assert_eq!(resolve("api.test", 443, &options(IpPreference::V4)).await?, vec![addr("10.0.0.1:443")]);
+        assert_eq!(resolve("api.test", 443, &options(IpPreference::V6)).await?, vec![addr("[fd00::1]:443")]);
+
+        assert_eq!(resolve("127.0.0.1", 80, &options(IpPreference::Any)).await?, vec![addr("127.0.0.1:80")]);
+        assert_eq!(resolve("::1", 80, &options(IpPreference::Any)).await?, vec![addr("[::1]:80")]);
+        let error = resolve("127.0.0.1", 80, &options(IpPreference::V6)).await.expect_err("No IPv6 address");
+        assert_eq!(error.downcast::<MyError>()?, MyError::IpResolve);
+        Ok(())
+    }
+}
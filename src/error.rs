@@ -1,13 +1,43 @@
 use strum_macros::Display;
 
-#[derive(Debug, Display)]
+#[derive(Debug, Display, Clone, Copy, PartialEq)]
 pub enum MyError {
     ConnectionClosedUnexpectedly,
     UnknownError,
     ZeroRead,
     HeaderParseError,
+    BareLineFeed,
+    ObsoleteLineFolding,
+    MissingHeaderColon,
+    InvalidHeaderName,
+    InvalidHeaderValue,
+    InvalidContentLength,
+    HeadersTooLarge,
+    TooManyHeaders,
+    InvalidChunk,
     IpResolve,
-    IdleTimeout
+    LocalPortsExhausted,
+    ProxyTunnelFailed,
+    ProxyAuthFailed,
+    ConnectTimeout,
+    TlsHandshakeTimeout,
+    FirstByteTimeout,
+    ReadTimeout,
+    RequestTimeout,
+    TooManyRedirects
+}
+
+impl MyError {
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            MyError::ConnectTimeout
+                | MyError::TlsHandshakeTimeout
+                | MyError::FirstByteTimeout
+                | MyError::ReadTimeout
+                | MyError::RequestTimeout
+        )
+    }
 }
 
 impl std::error::Error for MyError {
@@ -17,8 +47,25 @@ impl std::error::Error for MyError {
             MyError::UnknownError => None,
             MyError::ZeroRead => None,
             MyError::HeaderParseError => None,
+            MyError::BareLineFeed => None,
+            MyError::ObsoleteLineFolding => None,
+            MyError::MissingHeaderColon => None,
+            MyError::InvalidHeaderName => None,
+            MyError::InvalidHeaderValue => None,
+            MyError::InvalidContentLength => None,
+            MyError::HeadersTooLarge => None,
+            MyError::TooManyHeaders => None,
+            MyError::InvalidChunk => None,
             MyError::IpResolve => None,
-            MyError::IdleTimeout => None
+            MyError::LocalPortsExhausted => None,
+            MyError::ProxyTunnelFailed => None,
+            MyError::ProxyAuthFailed => None,
+            MyError::ConnectTimeout => None,
+            MyError::TlsHandshakeTimeout => None,
+            MyError::FirstByteTimeout => None,
+            MyError::ReadTimeout => None,
+            MyError::RequestTimeout => None,
+            MyError::TooManyRedirects => None
         }
     }
 }
\ No newline at end of file
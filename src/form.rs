@@ -0,0 +1,108 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use url::form_urlencoded;
+
+use crate::utils::NEWLINE;
+
+/// Form body, encoded once when the request is serialized.
+#[derive(Clone, Debug)]
+pub enum Form {
+    /// `application/x-www-form-urlencoded`
+    UrlEncoded(Vec<(String, String)>),
+    /// `multipart/form-data` (RFC 7578)
+    Multipart(Vec<Part>)
+}
+
+#[derive(Clone, Debug)]
+pub enum Part {
+    Text {
+        name: String,
+        value: String
+    },
+    File {
+        name: String,
+        file_name: String,
+        content_type: String,
+        content: Bytes
+    }
+}
+
+impl Form {
+    /// Encoded body and the matching `Content-Type`.
+    pub(crate) fn encode(&self) -> (Bytes, String) {
+        match self {
+            Form::UrlEncoded(fields) => {
+                let body = form_urlencoded::Serializer::new(String::new()).extend_pairs(fields).finish();
+                (Bytes::from(body), String::from("application/x-www-form-urlencoded"))
+            }
+            Form::Multipart(parts) => {
+                let boundary = format!("----http-client-{}", fastrand::u128(..));
+                let mut body = BytesMut::new();
+                for part in parts {
+                    body.put(format!("--{}{}", boundary, NEWLINE).as_bytes());
+                    match part {
+                        Part::Text { name, value } => {
+                            body.put(format!("Content-Disposition: form-data; name=\"{}\"{}", escape(name), NEWLINE).as_bytes());
+                            body.put(NEWLINE.as_bytes());
+                            body.put(value.as_bytes());
+                        }
+                        Part::File { name, file_name, content_type, content } => {
+                            body.put(format!(
+                                "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"{}",
+                                escape(name), escape(file_name), NEWLINE
+                            ).as_bytes());
+                            body.put(format!("Content-Type: {}{}", content_type, NEWLINE).as_bytes());
+                            body.put(NEWLINE.as_bytes());
+                            body.put(content.as_ref());
+                        }
+                    }
+                    body.put(NEWLINE.as_bytes());
+                }
+                body.put(format!("--{}--{}", boundary, NEWLINE).as_bytes());
+                (body.freeze(), format!("multipart/form-data; boundary={}", boundary))
+            }
+        }
+    }
+}
+
+/// Escapes a name for a quoted `Content-Disposition` parameter, the way browsers do.
+fn escape(name: &str) -> String {
+    name.replace('"', "%22").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use crate::form::{Form, Part};
+
+    #[test]
+    fn test_encode() {
+        let form = Form::UrlEncoded(vec![
+            (String::from("user"), String::from("bob smith")),
+            (String::from("q"), String::from("a&b=c"))
+        ]);
+        let (body, content_type) = form.encode();
+        assert_eq!(&body[..], b"user=bob+smith&q=a%26b%3Dc");
+        assert_eq!(content_type, "application/x-www-form-urlencoded");
+
+        let form = Form::Multipart(vec![
+            Part::Text { name: String::from("title"), value: String::from("Hello") },
+            Part::File {
+                name: String::from("upload"),
+                file_name: String::from("a\"b.bin"),
+                content_type: String::from("application/octet-stream"),
+                content: Bytes::from_static(&[0, 159, 146, 150])
+            }
+        ]);
+        let (body, content_type) = form.encode();
+        let boundary = content_type.strip_prefix("multipart/form-data; boundary=").expect("Boundary expected");
+        let mut expected = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHello\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"a%22b.bin\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            b = boundary
+        ).into_bytes();
+        expected.extend_from_slice(&[0, 159, 146, 150]);
+        expected.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        assert_eq!(&body[..], &expected[..]);
+    }
+}
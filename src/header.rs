@@ -1,7 +1,9 @@
 use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
 use anyhow::{Error};
-use crate::error::MyError::HeaderParseError;
+use crate::constants::{MAX_HEADERS, MAX_HEADER_BYTES};
+use crate::error::MyError;
+use crate::utils::is_token;
 
 #[derive(Debug)]
 pub struct HttpHeader {
@@ -9,22 +11,94 @@ pub struct HttpHeader {
     pub value: String
 }
 
+/// Limits on the header section of a response, checked while it is read.
+#[derive(Clone, Debug)]
+pub struct HeaderLimits {
+    /// Bytes of the status line and all header lines, line endings included.
+    /// Trailers and each chunk size line are held to the same limit.
+    pub max_bytes: usize,
+    pub max_count: usize,
+    /// Rejects lines ending in a bare LF, which RFC 9112, 2.2 lets a recipient
+    /// accept as a line terminator.
+    pub strict_line_endings: bool
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        HeaderLimits {
+            max_bytes: MAX_HEADER_BYTES,
+            max_count: MAX_HEADERS,
+            strict_line_endings: false
+        }
+    }
+}
+
 impl Display for HttpHeader {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.write_str(format!("{}: {}", self.name, self.value).as_str())
     }
 }
 
-impl TryFrom<String> for HttpHeader {
-    type Error = Error;
-    fn try_from(value: String) -> core::result::Result<Self, Self::Error> {
-        let parts: Vec<&str> = value.splitn(2, ": ").collect();
-        let name = *parts.first().ok_or(HeaderParseError)?;
-        let value = *parts.get(1).ok_or(HeaderParseError)?;
-        let value = &value[..value.len() - 2];
+impl HttpHeader {
+    /// Parses a field line without its line ending (RFC 9112, 5): a token, a colon
+    /// right after it, and a value with optional whitespace around it.
+    pub fn parse(line: &[u8]) -> Result<HttpHeader, MyError> {
+        if line.first().is_some_and(|byte| *byte == b' ' || *byte == b'\t') {
+            return Err(MyError::ObsoleteLineFolding);
+        }
+        let colon = line.iter().position(|byte| *byte == b':').ok_or(MyError::MissingHeaderColon)?;
+        let name = std::str::from_utf8(&line[..colon]).map_err(|_| MyError::InvalidHeaderName)?;
+        if !is_token(name) {
+            return Err(MyError::InvalidHeaderName);
+        }
+        let ows = |byte: &u8| *byte == b' ' || *byte == b'\t';
+        let value = &line[colon + 1..];
+        let start = value.iter().position(|byte| !ows(byte)).unwrap_or(value.len());
+        let end = value.iter().rposition(|byte| !ows(byte)).map_or(start, |end| end + 1);
+        let value = &value[start..end];
+        // Visible characters, obs-text, spaces and tabs, no CR, LF or NUL
+        if value.iter().any(|byte| byte.is_ascii_control() && *byte != b'\t') {
+            return Err(MyError::InvalidHeaderValue);
+        }
         Ok(HttpHeader {
             name: String::from(name),
-            value: String::from(value)
+            value: String::from_utf8_lossy(value).into_owned()
         })
     }
-}
\ No newline at end of file
+}
+
+/// Parses a field line ending in CRLF or a bare LF.
+impl TryFrom<String> for HttpHeader {
+    type Error = Error;
+    fn try_from(value: String) -> core::result::Result<Self, Self::Error> {
+        let line = value.strip_suffix('\n').ok_or(MyError::HeaderParseError)?;
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        Ok(HttpHeader::parse(line.as_bytes())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::MyError;
+    use crate::header::HttpHeader;
+
+    #[test]
+    fn test_parse() {
+        for line in ["Content-Type: text/plain", "Content-Type:text/plain", "Content-Type: \ttext/plain \t"] {
+            let header = HttpHeader::parse(line.as_bytes()).expect(line);
+            assert_eq!((header.name.as_str(), header.value.as_str()), ("Content-Type", "text/plain"));
+        }
+        let header = HttpHeader::parse(b"Empty:").expect("Empty value");
+        assert_eq!(header.value, "");
+
+        assert_eq!(HttpHeader::parse(b" folded value").err(), Some(MyError::ObsoleteLineFolding));
+        assert_eq!(HttpHeader::parse(b"No colon").err(), Some(MyError::MissingHeaderColon));
+        assert_eq!(HttpHeader::parse(b"Name : value").err(), Some(MyError::InvalidHeaderName));
+        assert_eq!(HttpHeader::parse(b": value").err(), Some(MyError::InvalidHeaderName));
+        assert_eq!(HttpHeader::parse(b"Name: a\rb").err(), Some(MyError::InvalidHeaderValue));
+        for line in ["Name: value\r\n", "Name: value\n"] {
+            assert_eq!(HttpHeader::try_from(String::from(line)).expect(line).value, "value");
+        }
+        assert!(HttpHeader::try_from(String::from("Name: value")).is_err());
+    }
+}
@@ -6,5 +6,14 @@ pub mod connection;
 pub mod response_reader;
 pub mod header;
 pub mod utils;
+pub mod tls;
+pub mod dns;
+pub mod proxy;
+pub mod socks;
+pub mod retry;
+pub mod redirect;
+pub mod cookie;
+pub mod compression;
+pub mod form;
 
 pub mod constants;
\ No newline at end of file
@@ -1,15 +1,27 @@
 use std::collections::HashMap;
 use std::{env, fs};
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
-use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::Bytes;
 use futures::future;
-use log::{debug};
+use log::{debug, warn};
 use tokio::fs::read_to_string;
-use http_client::client::HttpClient;
-use http_client::request::{Method, Request};
-use anyhow::Result;
+use http_client::client::{HttpClient, Retries};
+use http_client::compression::Encoding;
+use http_client::form::{Form, Part};
+use http_client::header::HeaderLimits;
+use http_client::connection::{AddressSelection, ConnectionOptions, IpPreference, ReuseOptions, SocketOptions};
+use http_client::proxy::ProxyOptions;
+use http_client::redirect::RedirectPolicy;
+use http_client::retry::{RetryOn, RetryPolicy, StatusPattern};
+use http_client::tls::{ClientIdentity, TlsOptions};
+use http_client::request::{Body, Method, Request, StreamingBody};
+use anyhow::{anyhow, Result};
 use serde::Deserialize;
 use url::Url;
 
@@ -21,7 +33,105 @@ static ALLOC: dhat::Alloc = dhat::Alloc;
 pub struct LoadTestRequest {
     pub request: RequestData,
     pub repeats: usize,
-    pub max_connections: usize
+    pub max_connections: usize,
+    #[serde(default)]
+    pub client_identity: Option<ClientIdentityData>,
+    #[serde(default)]
+    pub connection: ConnectionData,
+    #[serde(default)]
+    pub socket: SocketData,
+    /// `host:port` to comma separated addresses, like curl's `--resolve`.
+    #[serde(default)]
+    pub resolve: HashMap<String, String>,
+    /// Unix domain socket all requests are sent to.
+    #[serde(default)]
+    pub unix_socket: Option<PathBuf>,
+    /// Falls back to the `HTTP(S)_PROXY` and `NO_PROXY` environment variables.
+    #[serde(default)]
+    pub proxy: Option<ProxyOptions>,
+    #[serde(default)]
+    pub retry: RetryData,
+    #[serde(default)]
+    pub redirect: RedirectData,
+    /// Every virtual user keeps its own cookie jar.
+    #[serde(default)]
+    pub cookies: bool,
+    /// Codings to accept and decode, such as `[gzip, br, zstd]`.
+    #[serde(default)]
+    pub accept_encoding: Vec<Encoding>,
+    #[serde(default)]
+    pub tls: TlsOptions
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ConnectionData {
+    pub connect_timeout_ms: Option<u64>,
+    pub tls_handshake_timeout_ms: Option<u64>,
+    pub first_byte_timeout_ms: Option<u64>,
+    pub read_timeout_ms: Option<u64>,
+    pub request_timeout_ms: Option<u64>,
+    pub ip_preference: IpPreference,
+    pub address_selection: AddressSelection,
+    pub happy_eyeballs_delay_ms: Option<u64>,
+    pub local_addresses: Vec<IpAddr>,
+    pub local_port_range: Option<(u16, u16)>,
+    pub keep_alive: Option<bool>,
+    pub max_requests_per_connection: Option<usize>,
+    pub max_connection_lifetime_ms: Option<u64>,
+    /// Limits on the response header section, the status line included.
+    pub max_header_bytes: Option<usize>,
+    pub max_headers: Option<usize>,
+    /// Rejects header lines ending in a bare LF instead of CRLF.
+    pub strict_line_endings: Option<bool>
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct SocketData {
+    pub nodelay: Option<bool>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    pub linger_secs: Option<u64>,
+    pub reset_on_close: Option<bool>,
+    pub keepalive: Option<bool>,
+    pub keepalive_time_ms: Option<u64>,
+    pub keepalive_interval_ms: Option<u64>,
+    pub keepalive_retries: Option<u32>,
+    pub read_buffer_size: Option<usize>
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct RetryData {
+    pub max_attempts: Option<usize>,
+    pub retry_on: Option<Vec<RetryOn>>,
+    /// Codes such as `503` or classes such as `5xx`.
+    pub retry_statuses: Vec<StatusPattern>,
+    pub idempotent_only: Option<bool>,
+    pub backoff_base_ms: Option<u64>,
+    pub backoff_max_ms: Option<u64>,
+    pub jitter: Option<bool>
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct RedirectData {
+    /// Redirects are not followed unless set.
+    pub max_redirects: Option<usize>,
+    pub sensitive_headers: Option<Vec<String>>
+}
+
+/// Client certificate for mutual TLS, paths are relative to the scenario directory.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIdentityData {
+    Pem { cert: PathBuf, key: PathBuf },
+    Pkcs12 {
+        path: PathBuf,
+        #[serde(default)]
+        password: String
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -29,7 +139,52 @@ pub struct RequestData {
     pub query: String,
     pub method: String,
     pub headers: HashMap<String, String>,
-    pub body: Option<PathBuf>
+    #[serde(default)]
+    pub body: Option<BodyData>,
+    /// Compresses the body and sets `Content-Encoding`.
+    #[serde(default)]
+    pub compress: Option<Encoding>
+}
+
+/// Request body: a bare string is a file in the `body` directory of the scenario.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum BodyData {
+    Path(PathBuf),
+    Source(BodySource)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BodySource {
+    File(PathBuf),
+    Text(String),
+    Base64(String),
+    /// File sent with chunked transfer coding, read while the request is sent.
+    Stream(StreamData),
+    /// `application/x-www-form-urlencoded` fields.
+    Form(Vec<FieldData>),
+    /// `multipart/form-data` parts, text or files from the `body` directory.
+    Multipart(Vec<FieldData>)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FieldData {
+    pub name: String,
+    pub value: Option<String>,
+    pub file: Option<PathBuf>,
+    /// File name sent for a file part, the name of the file by default.
+    pub filename: Option<String>,
+    pub content_type: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StreamData {
+    pub file: PathBuf,
+    pub chunk_size: Option<usize>,
+    pub chunk_delay_ms: Option<u64>,
+    #[serde(default)]
+    pub trailers: HashMap<String, String>
 }
 
 pub fn to_request(data: &RequestData, working_dir: &Path) -> Result<Request> {
@@ -37,14 +192,150 @@ pub fn to_request(data: &RequestData, working_dir: &Path) -> Result<Request> {
         method: Method::from_str(&data.method)?,
         url: Url::parse(&data.query)?,
         headers: data.headers.clone(),
-        body: data.body.clone().map(|body| {
-            read_to_body(working_dir.join("body").join(body)).unwrap()
+        body: data.body.as_ref().map(|body| to_body(body, working_dir)).transpose()?,
+        compress: data.compress
+    })
+}
+
+pub fn to_connection_options(data: &LoadTestRequest, working_dir: &Path) -> Result<ConnectionOptions> {
+    let client_identity = match &data.client_identity {
+        Some(ClientIdentityData::Pem { cert, key }) => Some(ClientIdentity::from_pem(
+            &fs::read(working_dir.join(cert))?,
+            &fs::read(working_dir.join(key))?
+        )?),
+        Some(ClientIdentityData::Pkcs12 { path, password }) => Some(ClientIdentity::from_pkcs12(
+            &fs::read(working_dir.join(path))?,
+            password
+        )?),
+        None => None
+    };
+    let resolve = data.resolve.iter()
+        .map(|(host_port, ips)| {
+            let ips = ips.split(',').map(|ip| ip.trim().parse::<IpAddr>()).collect::<Result<Vec<_>, _>>()?;
+            Ok((host_port.clone(), ips))
         })
+        .collect::<Result<HashMap<String, Vec<IpAddr>>>>()?;
+    let defaults = ConnectionOptions::default();
+    let connection = &data.connection;
+    let socket = &data.socket;
+    let retry = &data.retry;
+    Ok(ConnectionOptions {
+        connect_timeout: connection.connect_timeout_ms.map(Duration::from_millis),
+        tls_handshake_timeout: connection.tls_handshake_timeout_ms.map(Duration::from_millis),
+        first_byte_timeout: connection.first_byte_timeout_ms.map(Duration::from_millis),
+        read_timeout: connection.read_timeout_ms.map_or(defaults.read_timeout, |ms| Some(Duration::from_millis(ms))),
+        request_timeout: connection.request_timeout_ms.map(Duration::from_millis),
+        ip_preference: connection.ip_preference,
+        address_selection: connection.address_selection,
+        happy_eyeballs_delay: connection.happy_eyeballs_delay_ms
+            .map_or(defaults.happy_eyeballs_delay, Duration::from_millis),
+        local_addresses: connection.local_addresses.clone(),
+        local_port_range: connection.local_port_range,
+        socket: SocketOptions {
+            nodelay: socket.nodelay.unwrap_or(defaults.socket.nodelay),
+            send_buffer_size: socket.send_buffer_size,
+            recv_buffer_size: socket.recv_buffer_size,
+            linger_secs: socket.linger_secs,
+            reset_on_close: socket.reset_on_close.unwrap_or(defaults.socket.reset_on_close),
+            keepalive: socket.keepalive.unwrap_or(defaults.socket.keepalive),
+            keepalive_time: socket.keepalive_time_ms.map(Duration::from_millis),
+            keepalive_interval: socket.keepalive_interval_ms.map(Duration::from_millis),
+            keepalive_retries: socket.keepalive_retries,
+            read_buffer_size: socket.read_buffer_size.unwrap_or(defaults.socket.read_buffer_size)
+        },
+        reuse: ReuseOptions {
+            keep_alive: connection.keep_alive.unwrap_or(defaults.reuse.keep_alive),
+            max_requests: connection.max_requests_per_connection,
+            max_lifetime: connection.max_connection_lifetime_ms.map(Duration::from_millis)
+        },
+        resolve,
+        proxy: match &data.proxy {
+            Some(proxy) => proxy.clone(),
+            None => ProxyOptions::from_env()?
+        },
+        unix_socket: data.unix_socket.clone(),
+        retry: RetryPolicy {
+            max_attempts: retry.max_attempts.unwrap_or(defaults.retry.max_attempts),
+            retry_on: retry.retry_on.clone().unwrap_or(defaults.retry.retry_on),
+            retry_statuses: retry.retry_statuses.clone(),
+            idempotent_only: retry.idempotent_only.unwrap_or(defaults.retry.idempotent_only),
+            backoff_base: retry.backoff_base_ms.map_or(defaults.retry.backoff_base, Duration::from_millis),
+            backoff_max: retry.backoff_max_ms.map_or(defaults.retry.backoff_max, Duration::from_millis),
+            jitter: retry.jitter.unwrap_or(defaults.retry.jitter)
+        },
+        redirect: RedirectPolicy {
+            max_redirects: data.redirect.max_redirects.unwrap_or(defaults.redirect.max_redirects),
+            sensitive_headers: data.redirect.sensitive_headers.clone().unwrap_or(defaults.redirect.sensitive_headers)
+        },
+        cookies: data.cookies,
+        accept_encoding: data.accept_encoding.clone(),
+        header_limits: HeaderLimits {
+            max_bytes: connection.max_header_bytes.unwrap_or(defaults.header_limits.max_bytes),
+            max_count: connection.max_headers.unwrap_or(defaults.header_limits.max_count),
+            strict_line_endings: connection.strict_line_endings.unwrap_or(defaults.header_limits.strict_line_endings)
+        },
+        tls: TlsOptions { client_identity, ..data.tls.clone() }
+    })
+}
+
+/// Outcome of the requests of a scenario. Retries are not requests of their own.
+#[derive(Debug, Default)]
+struct RequestCounts {
+    succeeded: usize,
+    failed: usize,
+    retries: usize,
+    redirects: usize,
+    received_bytes: usize,
+    decoded_bytes: usize
+}
+
+pub(crate) fn to_body(data: &BodyData, working_dir: &Path) -> Result<Body> {
+    Ok(match data {
+        BodyData::Path(path) | BodyData::Source(BodySource::File(path)) => {
+            Body::Bytes(read_to_body(working_dir.join("body").join(path))?)
+        }
+        BodyData::Source(BodySource::Text(text)) => Body::Bytes(Bytes::from(text.clone())),
+        BodyData::Source(BodySource::Base64(encoded)) => Body::Bytes(Bytes::from(BASE64.decode(encoded.trim())?)),
+        BodyData::Source(BodySource::Stream(stream)) => {
+            let defaults = StreamingBody::from_file(working_dir.join("body").join(&stream.file));
+            Body::Stream(StreamingBody {
+                chunk_size: stream.chunk_size.unwrap_or(defaults.chunk_size),
+                chunk_delay: stream.chunk_delay_ms.map(Duration::from_millis),
+                trailers: stream.trailers.iter().map(|(name, value)| (name.clone(), value.clone())).collect(),
+                ..defaults
+            })
+        }
+        BodyData::Source(BodySource::Form(fields)) => Body::Form(Form::UrlEncoded(
+            fields.iter()
+                .map(|field| match (&field.value, &field.file) {
+                    (Some(value), None) => Ok((field.name.clone(), value.clone())),
+                    _ => Err(anyhow!("Form field {} needs a value", field.name))
+                })
+                .collect::<Result<_>>()?
+        )),
+        BodyData::Source(BodySource::Multipart(fields)) => Body::Form(Form::Multipart(
+            fields.iter().map(|field| to_part(field, working_dir)).collect::<Result<_>>()?
+        ))
+    })
+}
+
+fn to_part(field: &FieldData, working_dir: &Path) -> Result<Part> {
+    Ok(match (&field.value, &field.file) {
+        (Some(value), None) => Part::Text { name: field.name.clone(), value: value.clone() },
+        (None, Some(file)) => Part::File {
+            name: field.name.clone(),
+            file_name: field.filename.clone().unwrap_or_else(|| {
+                file.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+            }),
+            content_type: field.content_type.clone().unwrap_or_else(|| String::from("application/octet-stream")),
+            content: read_to_body(working_dir.join("body").join(file))?
+        },
+        _ => return Err(anyhow!("Multipart field {} needs either a value or a file", field.name))
     })
 }
 
-pub(crate) fn read_to_body(path: PathBuf) -> Result<Arc<Pin<String>>> {
-    Ok(Arc::new(Pin::new(fs::read_to_string(path)?)))
+pub(crate) fn read_to_body(path: PathBuf) -> Result<Bytes> {
+    Ok(Bytes::from(fs::read(path)?))
 }
 
 #[tokio::main]
@@ -78,10 +369,11 @@ async fn main() -> Result<()> {
         let requests_per_connection = req_data.repeats / req_data.max_connections;
 
         let mut request: Request = to_request(&req_data.request, &path)?;
+        let options = to_connection_options(&req_data, &path)?;
 
         let url = Arc::new(request.url.clone());
 
-        let ready_request = Arc::new(request.get_raw().await);
+        let ready_request = Arc::new(request.get_raw().await?);
 
         let mut handles = Vec::with_capacity(req_data.max_connections);
 
@@ -90,28 +382,68 @@ async fn main() -> Result<()> {
 
         for _ in 0..req_data.max_connections {
             let url = url.clone();
-            let mut client = HttpClient::new().await;
+            let mut client = HttpClient::with_options(options.clone()).await?;
             let ready_request = ready_request.clone();
-            debug!("{:?}", &ready_request.0);
+            debug!("{:?}", &ready_request);
             handles.push(tokio::spawn(async move {
+                let mut counts = RequestCounts::default();
                 for _ in 0..requests_per_connection {
                     debug!("=======================================================================");
-                    let response = client.perform_request(&url, ready_request.clone()).await.unwrap();
+                    let response = match client.perform_request(&url, ready_request.clone()).await {
+                        Ok(response) => response,
+                        Err(error) => {
+                            warn!("Request failed: {:#}", error);
+                            counts.failed += 1;
+                            counts.retries += error.downcast_ref::<Retries>().map_or(0, |retries| retries.0);
+                            continue;
+                        }
+                    };
                     debug!("Read headers: {:?}", response.headers);
+                    counts.retries += response.retries;
+                    counts.redirects += response.redirects.len();
+                    let mut succeeded = response.status < 400;
                     if let Some(mut body_reader) = response.body_reader {
                         let mut response_body: Vec<u8> = Vec::new();
                         while let Some(buf) = body_reader.recv().await {
-                            response_body.extend_from_slice(&buf);
+                            match buf {
+                                Ok(buf) => response_body.extend_from_slice(&buf),
+                                Err(error) => {
+                                    warn!("Reading body failed: {}", error);
+                                    succeeded = false;
+                                }
+                            }
                         }
                         debug!("Read body: {}", String::from_utf8_lossy(&response_body));
                     }
+                    counts.received_bytes += response.body_stats.received();
+                    counts.decoded_bytes += response.body_stats.decoded();
+                    if succeeded {
+                        counts.succeeded += 1;
+                    } else {
+                        counts.failed += 1;
+                    }
                 }
+                counts
             }));
         }
 
-        future::join_all(handles).await;
+        let mut counts = RequestCounts::default();
+        for handle in future::join_all(handles).await {
+            let handle = handle?;
+            counts.succeeded += handle.succeeded;
+            counts.failed += handle.failed;
+            counts.retries += handle.retries;
+            counts.redirects += handle.redirects;
+            counts.received_bytes += handle.received_bytes;
+            counts.decoded_bytes += handle.decoded_bytes;
+        }
 
         println!("Time spent: {}", before.elapsed().unwrap().as_millis());
+        println!(
+            "Succeeded: {}, failed: {}, retries: {}, redirects: {}",
+            counts.succeeded, counts.failed, counts.retries, counts.redirects
+        );
+        println!("Body bytes received: {}, decoded: {}", counts.received_bytes, counts.decoded_bytes);
     }
     // STATISTICS.lock().await.print();
     Ok(())
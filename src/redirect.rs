@@ -0,0 +1,127 @@
+use url::Url;
+use anyhow::Result;
+
+use crate::header::HttpHeader;
+use crate::request::{Method, ReadyRequest};
+
+/// Headers describing the body, dropped together with it when a redirect changes the method to `GET`.
+const CONTENT_HEADERS: [&str; 4] = ["Content-Type", "Content-Length", "Content-Encoding", "Transfer-Encoding"];
+
+#[derive(Clone, Debug)]
+pub struct RedirectPolicy {
+    /// Redirects followed for a single request, 0 returns 3xx responses as they are.
+    pub max_redirects: usize,
+    /// Removed from the request when a redirect leads to another origin.
+    pub sensitive_headers: Vec<String>
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        RedirectPolicy {
+            max_redirects: 0,
+            sensitive_headers: vec![
+                String::from("Authorization"),
+                String::from("Cookie"),
+                String::from("Proxy-Authorization")
+            ]
+        }
+    }
+}
+
+/// A followed redirect: the status of the response and the URL it pointed to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Redirect {
+    pub status: u32,
+    pub location: Url
+}
+
+impl RedirectPolicy {
+    /// Target of a redirect response, resolved against the URL of the request. Only
+    /// HTTP(S) URLs with a host are followed, a server can't send the client to a
+    /// local socket or another kind of URL.
+    pub(crate) fn location(&self, url: &Url, status: u32, headers: &[HttpHeader]) -> Option<Url> {
+        if self.max_redirects == 0 || !matches!(status, 301 | 302 | 303 | 307 | 308) {
+            return None;
+        }
+        let location = headers.iter().find(|header| header.name.eq_ignore_ascii_case("Location"))?;
+        url.join(location.value.trim()).ok()
+            .filter(|location| matches!(location.scheme(), "http" | "https") && location.has_host())
+    }
+
+    /// Request to send to the location. 301 and 302 turn a `POST` into a `GET`, as browsers
+    /// do, 303 turns everything but `HEAD` into a `GET`, 307 and 308 keep method and body.
+    pub(crate) fn follow(&self, request: &ReadyRequest, url: &Url, status: u32, location: &Url) -> Result<ReadyRequest> {
+        let to_get = match status {
+            301 | 302 => request.method == Method::POST,
+            303 => request.method != Method::HEAD,
+            _ => false
+        };
+        let mut headers = request.header_fields.clone();
+        if to_get {
+            headers.retain(|name, _| !CONTENT_HEADERS.iter().any(|header| header.eq_ignore_ascii_case(name)));
+        }
+        if url.origin() != location.origin() {
+            headers.retain(|name, _| !self.sensitive_headers.iter().any(|header| header.eq_ignore_ascii_case(name)));
+        }
+        if to_get {
+            ReadyRequest::new(Method::GET, location, headers, None, None)
+        } else {
+            ReadyRequest::new(request.method.clone(), location, headers, request.body.clone(), request.content_encoding)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use bytes::Bytes;
+    use anyhow::Result;
+    use url::Url;
+    use crate::compression::Encoding;
+    use crate::header::HttpHeader;
+    use crate::redirect::RedirectPolicy;
+    use crate::request::{Body, Method, Payload, Request};
+
+    #[tokio::test]
+    async fn test_follow() -> Result<()> {
+        let policy = RedirectPolicy { max_redirects: 5, ..RedirectPolicy::default() };
+        let url = Url::parse("http://example.com/a/b")?;
+        let headers = vec![HttpHeader { name: String::from("location"), value: String::from("../c") }];
+        let location = policy.location(&url, 302, &headers).expect("Location expected");
+        assert_eq!(location.as_str(), "http://example.com/c");
+        assert!(policy.location(&url, 304, &headers).is_none());
+        for location in ["mailto:a@b.c", "data:text/plain,x", "about:blank", "unix:/run/app.sock:/", "file:///etc/passwd"] {
+            let headers = vec![HttpHeader { name: String::from("Location"), value: String::from(location) }];
+            assert!(policy.location(&url, 302, &headers).is_none(), "{}", location);
+        }
+
+        let mut request = Request {
+            method: Method::POST,
+            url: url.clone(),
+            headers: HashMap::from([
+                (String::from("Authorization"), String::from("Bearer secret")),
+                (String::from("Content-Type"), String::from("application/json"))
+            ]),
+            body: Some(Body::Bytes(Bytes::from_static(b"{}"))),
+            compress: Some(Encoding::Gzip)
+        };
+        let request = request.get_raw().await?;
+
+        let same_origin = policy.follow(&request, &url, 307, &location)?;
+        assert_eq!(same_origin.method, Method::POST);
+        assert!(matches!(
+            (&same_origin.body, &request.body),
+            (Some(Payload::Bytes(redirected)), Some(Payload::Bytes(original))) if redirected == original
+        ));
+        assert!(String::from_utf8_lossy(&same_origin.headers).contains("Content-Encoding: gzip\r\n"));
+        assert_eq!(same_origin.header_fields.len(), 2);
+
+        let cross_origin = policy.follow(&request, &url, 303, &Url::parse("https://other.com/")?)?;
+        assert_eq!(cross_origin.method, Method::GET);
+        assert!(cross_origin.body.is_none());
+        assert!(cross_origin.header_fields.is_empty());
+        assert!(cross_origin.request_line.starts_with(b"GET / HTTP/1.1"));
+        assert!(!String::from_utf8_lossy(&cross_origin.headers).contains("Content-Encoding"));
+        Ok(())
+    }
+}
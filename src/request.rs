@@ -1,58 +1,407 @@
 use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
-use bytes::Bytes;
+use std::time::Duration;
+use bytes::{Bytes, BytesMut};
+use futures::stream::{self, Stream, StreamExt};
 use strum_macros::{Display, EnumString};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::sleep;
 use url::Url;
 
-use crate::utils::NEWLINE;
+use anyhow::{anyhow, Result};
+use crate::compression::{compress, Encoding};
+use crate::constants::STREAM_CHUNK_SIZE;
+use crate::form::Form;
+use crate::utils::{is_token, unix_socket_target, NEWLINE, NEWLINE_BYTES};
 
-#[derive(Display, Debug, PartialEq, EnumString)]
+#[derive(Display, Debug, Clone, PartialEq, EnumString)]
 pub enum Method {
     GET,
     POST,
     PUT,
     DELETE,
     OPTIONS,
-    HEAD
+    HEAD,
+    PATCH,
+    TRACE,
+    CONNECT,
+    /// Any other method, such as WebDAV's `PROPFIND`. Method names are case-sensitive.
+    #[strum(default, to_string = "{0}")]
+    Extension(String)
 }
 
-pub(crate) type BodyType = Option<Arc<Pin<String>>>;
+impl Method {
+    /// Whether sending the request twice has the same effect as sending it once (RFC 9110, 9.2.2).
+    /// Extension methods are assumed not to be.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(self, Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE)
+    }
+
+    /// Whether the request may carry a body. TRACE must not (RFC 9110, 9.3.8), and
+    /// a CONNECT body has no meaning to the proxy.
+    pub fn allows_body(&self) -> bool {
+        !matches!(self, Method::TRACE | Method::CONNECT)
+    }
+
+    /// Whether a response with the status has a body, as far as the method tells.
+    /// HEAD responses never do, and a successful CONNECT turns the connection into a tunnel.
+    pub fn expects_response_body(&self, status: u32) -> bool {
+        match self {
+            Method::HEAD => false,
+            Method::CONNECT => !(200..300).contains(&status),
+            _ => true
+        }
+    }
+}
+
+pub(crate) type BodyType = Option<Body>;
+
+pub type BodyStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+#[derive(Clone, Debug)]
+pub enum Body {
+    /// Sent with `Content-Length`.
+    Bytes(Bytes),
+    /// Sent with `Transfer-Encoding: chunked` while it is produced.
+    Stream(StreamingBody),
+    /// Encoded when the request is serialized, `Content-Type` is set unless given.
+    Form(Form)
+}
+
+/// Body of a serialized request, as it goes on the wire.
+#[derive(Clone, Debug)]
+pub(crate) enum Payload {
+    Bytes(Bytes),
+    Stream(StreamingBody)
+}
+
+/// Where a streamed body comes from. It is opened anew for every send, so
+/// that retries and redirects can send it again.
+#[derive(Clone)]
+pub enum StreamSource {
+    /// Read lazily, one chunk at a time.
+    File(PathBuf),
+    Stream(Arc<dyn Fn() -> BodyStream + Send + Sync>)
+}
+
+impl Debug for StreamSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamSource::File(path) => f.debug_tuple("File").field(path).finish(),
+            StreamSource::Stream(_) => f.write_str("Stream")
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StreamingBody {
+    pub source: StreamSource,
+    /// Size of the chunks read from a file.
+    pub chunk_size: usize,
+    /// Pause after every chunk, to simulate slow uploads.
+    pub chunk_delay: Option<Duration>,
+    /// Sent after the last chunk and announced in the `Trailer` header.
+    pub trailers: Vec<(String, String)>
+}
+
+impl StreamingBody {
+    pub fn from_file(path: PathBuf) -> StreamingBody {
+        StreamingBody {
+            source: StreamSource::File(path),
+            chunk_size: STREAM_CHUNK_SIZE,
+            chunk_delay: None,
+            trailers: Vec::new()
+        }
+    }
+
+    fn open(&self) -> BodyStream {
+        match &self.source {
+            StreamSource::File(path) => {
+                let chunk_size = self.chunk_size.max(1);
+                Box::pin(stream::try_unfold((path.clone(), None), move |(path, file): (PathBuf, Option<File>)| async move {
+                    let mut file = match file {
+                        Some(file) => file,
+                        None => File::open(&path).await?
+                    };
+                    let mut chunk = BytesMut::with_capacity(chunk_size);
+                    if file.read_buf(&mut chunk).await? == 0 {
+                        return Ok(None);
+                    }
+                    Ok(Some((chunk.freeze(), (path, Some(file)))))
+                }))
+            }
+            StreamSource::Stream(open) => open()
+        }
+    }
+
+    /// Writes the body in chunked transfer coding (RFC 9112, 7.1), followed by the trailers.
+    pub(crate) async fn write_chunked<T: AsyncWriteExt + Unpin>(&self, writer: &mut T) -> Result<()> {
+        let mut stream = self.open();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            // An empty chunk would end the body
+            if chunk.is_empty() {
+                continue;
+            }
+            writer.write_all(format!("{:x}{}", chunk.len(), NEWLINE).as_bytes()).await?;
+            writer.write_all(&chunk).await?;
+            writer.write_all(NEWLINE_BYTES).await?;
+            if let Some(delay) = self.chunk_delay {
+                writer.flush().await?;
+                sleep(delay).await;
+            }
+        }
+        let mut last = format!("0{}", NEWLINE);
+        for (name, value) in &self.trailers {
+            last.push_str(&format!("{}: {}{}", name, value, NEWLINE));
+        }
+        last.push_str(NEWLINE);
+        writer.write_all(last.as_bytes()).await?;
+        Ok(())
+    }
+}
 
 pub struct Request {
     pub method: Method,
     pub url: Url,
     pub headers: HashMap<String, String>,
-    pub body: BodyType
+    pub body: BodyType,
+    /// Compresses the body once, when the request is serialized, and sets `Content-Encoding`.
+    pub compress: Option<Encoding>
 }
 
-pub(crate) type ReadyRequest = (Pin<Box<Bytes>>, BodyType);
+/// Request serialized once and sent many times. The request line is kept
+/// apart from the headers so that it can be sent in absolute form to proxies.
+#[derive(Debug)]
+pub struct ReadyRequest {
+    pub(crate) method: Method,
+    pub(crate) request_line: Bytes,
+    pub(crate) absolute_request_line: Bytes,
+    pub(crate) headers: Pin<Box<Bytes>>,
+    /// User headers, kept to derive the request that follows a redirect.
+    pub(crate) header_fields: HashMap<String, String>,
+    /// Body as sent, after compression.
+    pub(crate) body: Option<Payload>,
+    pub(crate) content_encoding: Option<Encoding>
+}
 
 impl Request {
-    pub async fn get_raw(&mut self) -> ReadyRequest {
+    pub async fn get_raw(&mut self) -> Result<ReadyRequest> {
+        if let Method::Extension(name) = &self.method {
+            if !is_token(name) {
+                return Err(anyhow!("Invalid method {:?}", name));
+            }
+        }
+        if self.body.is_some() && !self.method.allows_body() {
+            return Err(anyhow!("{} requests can't have a body", self.method));
+        }
         /*let body: Option<Pin<Box<String>>> = self.body.as_mut().map(|mut body| {
             Pin::new(Box::new(body.by_ref().collect()))
         });*/
+        let mut headers = self.headers.clone();
+        let body = match &self.body {
+            Some(Body::Bytes(body)) => Some(Payload::Bytes(body.clone())),
+            Some(Body::Stream(body)) => Some(Payload::Stream(body.clone())),
+            Some(Body::Form(form)) => {
+                let (body, content_type) = form.encode();
+                if !headers.keys().any(|name| name.eq_ignore_ascii_case("Content-Type")) {
+                    headers.insert(String::from("Content-Type"), content_type);
+                }
+                Some(Payload::Bytes(body))
+            }
+            None => None
+        };
+        let body = match (body, self.compress) {
+            (Some(Payload::Bytes(body)), Some(encoding)) => Some(Payload::Bytes(Bytes::from(compress(encoding, &body)?))),
+            (Some(Payload::Stream(_)), Some(_)) => return Err(anyhow!("Streamed bodies can't be compressed")),
+            (body, _) => body
+        };
+        ReadyRequest::new(self.method.clone(), &self.url, headers, body, self.compress)
+    }
+}
+
+impl ReadyRequest {
+    /// Serializes the request line and headers, the body is expected to be encoded already.
+    pub(crate) fn new(
+        method: Method,
+        url: &Url,
+        header_fields: HashMap<String, String>,
+        body: Option<Payload>,
+        content_encoding: Option<Encoding>
+    ) -> Result<ReadyRequest> {
         let mut lines = Vec::with_capacity(20);
-        let query = &self.url.query().map_or_else(|| {
-            self.url.path().to_owned()
+        let (path, host) = match unix_socket_target(url) {
+            Some((_, path)) => (path, String::from("localhost")),
+            None => (url.path(), url.host().ok_or_else(|| anyhow!("URL {} has no host", url))?.to_string())
+        };
+        let query = &url.query().map_or_else(|| {
+            path.to_owned()
         }, |q|{
-            format!("{}?{}", &self.url.path(), q)
+            format!("{}?{}", path, q)
         });
-        lines.push(format!("{} {} HTTP/1.1", &self.method, query));
-        lines.push(format!("Host: {}", &self.url.host().expect("Invalid host")));
-        self.headers.iter().for_each(|(name, value)|{
+        let (request_line, absolute_request_line) = if method == Method::CONNECT {
+            // CONNECT names the tunnel target in authority form (RFC 9112, 3.2.3), proxied or not
+            let authority = format!("{} {}:{} HTTP/1.1{}", &method, host, url.port_or_known_default().unwrap_or(80), NEWLINE);
+            (authority.clone(), authority)
+        } else {
+            let mut absolute_url = url.clone();
+            absolute_url.set_fragment(None);
+            (
+                format!("{} {} HTTP/1.1{}", &method, query, NEWLINE),
+                format!("{} {} HTTP/1.1{}", &method, absolute_url, NEWLINE)
+            )
+        };
+        lines.push(format!("Host: {}", host));
+        header_fields.iter().for_each(|(name, value)|{
             lines.push(format!("{}: {}", name, value));
         });
-        let body_len = match &self.body {
-            Some(body) => body.len(),
-            None => 0
-        };
-        lines.push(format!("Content-Length: {}", body_len));
+        let content_encoding = content_encoding.filter(|_| body.is_some());
+        if let Some(encoding) = content_encoding {
+            lines.push(format!("Content-Encoding: {}", encoding));
+        }
+        match &body {
+            Some(Payload::Stream(stream)) => {
+                lines.push(String::from("Transfer-Encoding: chunked"));
+                if !stream.trailers.is_empty() {
+                    let names: Vec<&str> = stream.trailers.iter().map(|(name, _)| name.as_str()).collect();
+                    lines.push(format!("Trailer: {}", names.join(", ")));
+                }
+            }
+            Some(Payload::Bytes(body)) => lines.push(format!("Content-Length: {}", body.len())),
+            None if method.allows_body() => lines.push(String::from("Content-Length: 0")),
+            None => ()
+        }
         lines.push(String::from(""));
         lines.push(String::from(""));
 
         let headers_raw = Pin::new(Box::new(Bytes::from(lines.join(NEWLINE))));
-        (headers_raw, self.body.clone())
+        Ok(ReadyRequest {
+            method,
+            request_line: Bytes::from(request_line),
+            absolute_request_line: Bytes::from(absolute_request_line),
+            headers: headers_raw,
+            header_fields,
+            body,
+            content_encoding
+        })
+    }
+
+    /// Copy of the request carrying the cookies of a jar. They join a `Cookie` header
+    /// set by the user, a request has at most one (RFC 6265, 5.4).
+    pub(crate) fn with_cookies(&self, url: &Url, cookies: &str) -> Result<ReadyRequest> {
+        let Some(name) = self.header_fields.keys().find(|name| name.eq_ignore_ascii_case("Cookie")) else {
+            return Ok(self.with_header("Cookie", cookies));
+        };
+        let mut header_fields = self.header_fields.clone();
+        let value = format!("{}; {}", header_fields[name], cookies);
+        header_fields.insert(name.clone(), value);
+        ReadyRequest::new(self.method.clone(), url, header_fields, self.body.clone(), self.content_encoding)
+    }
+
+    /// Copy of the request with one more header, sent before the user headers.
+    pub(crate) fn with_header(&self, name: &str, value: &str) -> ReadyRequest {
+        let mut headers = format!("{}: {}{}", name, value, NEWLINE).into_bytes();
+        headers.extend_from_slice(&self.headers);
+        ReadyRequest {
+            method: self.method.clone(),
+            request_line: self.request_line.clone(),
+            absolute_request_line: self.absolute_request_line.clone(),
+            headers: Pin::new(Box::new(Bytes::from(headers))),
+            header_fields: self.header_fields.clone(),
+            body: self.body.clone(),
+            content_encoding: self.content_encoding
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use bytes::Bytes;
+    use futures::stream;
+    use anyhow::Result;
+    use url::Url;
+    use crate::request::{Body, Method, Request, StreamSource, StreamingBody};
+
+    #[tokio::test]
+    async fn test_chunked_body() -> Result<()> {
+        let body = StreamingBody {
+            source: StreamSource::Stream(Arc::new(|| {
+                Box::pin(stream::iter(["Hello, ", "", "streamed world"].map(|chunk| Ok(Bytes::from(chunk)))))
+            })),
+            chunk_size: 0,
+            chunk_delay: None,
+            trailers: vec![(String::from("X-Checksum"), String::from("abc"))]
+        };
+        let mut request = Request {
+            method: Method::PUT,
+            url: Url::parse("http://example.com/upload")?,
+            headers: HashMap::new(),
+            body: Some(Body::Stream(body.clone())),
+            compress: None
+        };
+        let headers = request.get_raw().await?.headers;
+        assert!(headers.ends_with(b"Transfer-Encoding: chunked\r\nTrailer: X-Checksum\r\n\r\n"));
+
+        // Every send opens the stream again
+        for _ in 0..2 {
+            let mut written = Vec::new();
+            body.write_chunked(&mut written).await?;
+            assert_eq!(written, b"7\r\nHello, \r\ne\r\nstreamed world\r\n0\r\nX-Checksum: abc\r\n\r\n");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_methods() -> Result<()> {
+        assert_eq!(Method::from_str("PATCH")?, Method::PATCH);
+        let propfind = Method::from_str("PROPFIND")?;
+        assert_eq!(propfind, Method::Extension(String::from("PROPFIND")));
+        assert_eq!(propfind.to_string(), "PROPFIND");
+        assert!(!propfind.is_idempotent() && Method::TRACE.is_idempotent());
+        assert!(!Method::HEAD.expects_response_body(200) && !Method::CONNECT.expects_response_body(200));
+
+        let mut request = Request {
+            method: Method::CONNECT,
+            url: Url::parse("https://example.com/ignored")?,
+            headers: HashMap::new(),
+            body: None,
+            compress: None
+        };
+        let ready = request.get_raw().await?;
+        assert_eq!(&ready.request_line[..], b"CONNECT example.com:443 HTTP/1.1\r\n");
+        assert!(!ready.headers.windows(14).any(|window| window == b"Content-Length"));
+
+        request.method = Method::TRACE;
+        request.body = Some(Body::Bytes(Bytes::from_static(b"x")));
+        assert!(request.get_raw().await.is_err());
+        request.method = Method::from_str("BAD METHOD")?;
+        request.body = None;
+        assert!(request.get_raw().await.is_err());
+        request.method = Method::GET;
+        request.url = Url::parse("mailto:a@b.c")?;
+        assert!(request.get_raw().await.is_err());
+        Ok(())
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_with_cookies() -> Result<()> {
+        let url = Url::parse("http://example.com/")?;
+        let mut request = Request { method: Method::GET, url: url.clone(), headers: HashMap::new(), body: None, compress: None };
+        let headers = request.get_raw().await?.with_cookies(&url, "session=abc")?.headers;
+        assert!(headers.starts_with(b"Cookie: session=abc\r\n"));
+
+        request.headers.insert(String::from("cookie"), String::from("theme=dark"));
+        let headers = request.get_raw().await?.with_cookies(&url, "session=abc")?.headers;
+        let headers = String::from_utf8_lossy(&headers);
+        assert_eq!(headers.matches("ookie:").count(), 1);
+        assert!(headers.contains("cookie: theme=dark; session=abc\r\n"));
+        Ok(())
+    }
+}
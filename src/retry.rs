@@ -0,0 +1,165 @@
+use std::io;
+use std::time::Duration;
+use serde::Deserialize;
+
+use anyhow::{anyhow, Error, Result};
+use crate::constants::{RETRY_BACKOFF_BASE, RETRY_BACKOFF_MAX};
+use crate::error::MyError;
+
+/// Error classes a request may be retried on.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// The server closed or reset the connection, typically a stale keep-alive connection.
+    ConnectionClosed,
+    /// The connection could not be established.
+    Connect,
+    /// Any of the connection timeouts expired.
+    Timeout
+}
+
+/// Response status to retry on, either an exact code or a class such as `5xx`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "StatusPatternData")]
+pub enum StatusPattern {
+    Code(u32),
+    Class(u32)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StatusPatternData {
+    Code(u32),
+    Text(String)
+}
+
+impl TryFrom<StatusPatternData> for StatusPattern {
+    type Error = Error;
+    fn try_from(value: StatusPatternData) -> Result<Self> {
+        match value {
+            StatusPatternData::Code(code) => Ok(StatusPattern::Code(code)),
+            StatusPatternData::Text(text) => {
+                let text = text.to_ascii_lowercase();
+                match text.strip_suffix("xx").and_then(|class| class.parse::<u32>().ok()) {
+                    Some(class @ 1..=5) if text.len() == 3 => Ok(StatusPattern::Class(class)),
+                    _ => text.parse().map(StatusPattern::Code).map_err(|_| anyhow!("Invalid status pattern: {}", text))
+                }
+            }
+        }
+    }
+}
+
+impl StatusPattern {
+    fn matches(&self, status: u32) -> bool {
+        match self {
+            StatusPattern::Code(code) => *code == status,
+            StatusPattern::Class(class) => status / 100 == *class
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts including the first one, 1 disables retries.
+    pub max_attempts: usize,
+    pub retry_on: Vec<RetryOn>,
+    pub retry_statuses: Vec<StatusPattern>,
+    /// Only retry methods that are idempotent.
+    pub idempotent_only: bool,
+    /// Delay before the first retry, doubled for every further one.
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// Picks a random delay up to the exponential backoff ("full jitter").
+    pub jitter: bool
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 2,
+            retry_on: vec![RetryOn::ConnectionClosed],
+            retry_statuses: Vec::new(),
+            idempotent_only: true,
+            backoff_base: Duration::from_millis(RETRY_BACKOFF_BASE),
+            backoff_max: Duration::from_millis(RETRY_BACKOFF_MAX),
+            jitter: true
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub(crate) fn retries_error(&self, error: &Error) -> bool {
+        classify(error).is_some_and(|class| self.retry_on.contains(&class))
+    }
+
+    pub(crate) fn retries_status(&self, status: u32) -> bool {
+        self.retry_statuses.iter().any(|pattern| pattern.matches(status))
+    }
+
+    /// Delay before the given retry, counting from 1.
+    pub(crate) fn backoff(&self, retry: usize) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31) as u32;
+        let delay = self.backoff_base.saturating_mul(1 << exponent).min(self.backoff_max);
+        if self.jitter {
+            delay.mul_f64(fastrand::f64())
+        } else {
+            delay
+        }
+    }
+}
+
+fn classify(error: &Error) -> Option<RetryOn> {
+    if let Some(error) = error.downcast_ref::<MyError>() {
+        return match error {
+            MyError::ConnectionClosedUnexpectedly | MyError::ZeroRead => Some(RetryOn::ConnectionClosed),
+            MyError::IpResolve | MyError::ProxyTunnelFailed | MyError::LocalPortsExhausted => Some(RetryOn::Connect),
+            // The deadline covers all attempts, another one cannot finish in time
+            MyError::RequestTimeout => None,
+            error if error.is_timeout() => Some(RetryOn::Timeout),
+            _ => None
+        };
+    }
+    match error.downcast_ref::<io::Error>()?.kind() {
+        io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::UnexpectedEof => Some(RetryOn::ConnectionClosed),
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::AddrNotAvailable
+        | io::ErrorKind::HostUnreachable
+        | io::ErrorKind::NetworkUnreachable => Some(RetryOn::Connect),
+        io::ErrorKind::TimedOut => Some(RetryOn::Timeout),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+    use anyhow::{anyhow, Result};
+    use crate::error::MyError;
+    use crate::retry::{RetryOn, RetryPolicy, StatusPattern};
+
+    #[test]
+    fn test_policy() -> Result<()> {
+        let policy = RetryPolicy {
+            retry_on: vec![RetryOn::ConnectionClosed, RetryOn::Timeout],
+            retry_statuses: serde_yaml::from_str("[429, 5xx]")?,
+            jitter: false,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.retry_statuses, vec![StatusPattern::Code(429), StatusPattern::Class(5)]);
+        assert!(policy.retries_status(503) && policy.retries_status(429) && !policy.retries_status(404));
+        assert!(policy.retries_error(&anyhow!(MyError::ConnectionClosedUnexpectedly)));
+        assert!(policy.retries_error(&anyhow!(io::Error::from(io::ErrorKind::ConnectionReset))));
+        assert!(policy.retries_error(&anyhow!(MyError::FirstByteTimeout)));
+        assert!(!policy.retries_error(&anyhow!(MyError::RequestTimeout)));
+        assert!(!policy.retries_error(&anyhow!(io::Error::from(io::ErrorKind::ConnectionRefused))));
+        assert_eq!(policy.backoff(1), Duration::from_millis(50));
+        assert_eq!(policy.backoff(3), Duration::from_millis(200));
+        assert_eq!(policy.backoff(10), Duration::from_secs(2));
+        assert!(serde_yaml::from_str::<StatusPattern>("\"6xx\"").is_err());
+        Ok(())
+    }
+}
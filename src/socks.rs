@@ -0,0 +1,176 @@
+use std::net::{IpAddr, SocketAddr};
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use anyhow::{anyhow, Result};
+use crate::error::MyError::{ProxyAuthFailed, ProxyTunnelFailed};
+
+const VERSION: u8 = 0x05;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_PASSWORD: u8 = 0x02;
+const AUTH_UNACCEPTABLE: u8 = 0xff;
+const PASSWORD_VERSION: u8 = 0x01;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Destination of a SOCKS5 `CONNECT`. Domains are resolved by the proxy.
+pub(crate) enum SocksTarget<'a> {
+    Domain(&'a str, u16),
+    Addr(SocketAddr)
+}
+
+/// Runs the SOCKS5 handshake (RFC 1928, RFC 1929) on a stream connected to the proxy.
+pub(crate) async fn connect<T>(
+    stream: &mut T,
+    target: SocksTarget<'_>,
+    credentials: Option<&(String, String)>
+) -> Result<()>
+where T: AsyncRead + AsyncWrite + Unpin
+{
+    let method = if credentials.is_some() { AUTH_PASSWORD } else { AUTH_NONE };
+    stream.write_all(&[VERSION, 1, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(anyhow!(ProxyTunnelFailed));
+    }
+    match (reply[1], credentials) {
+        (AUTH_NONE, _) => (),
+        (AUTH_PASSWORD, Some((user, password))) => {
+            if user.len() > 255 || password.len() > 255 {
+                return Err(anyhow!("SOCKS5 credentials longer than 255 bytes"));
+            }
+            let mut request = Vec::with_capacity(3 + user.len() + password.len());
+            request.push(PASSWORD_VERSION);
+            request.push(user.len() as u8);
+            request.extend_from_slice(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(anyhow!(ProxyAuthFailed));
+            }
+        }
+        (AUTH_UNACCEPTABLE, _) | (AUTH_PASSWORD, None) => return Err(anyhow!(ProxyAuthFailed)),
+        _ => return Err(anyhow!(ProxyTunnelFailed))
+    }
+
+    let mut request = vec![VERSION, CMD_CONNECT, 0x00];
+    let port = match target {
+        SocksTarget::Domain(host, port) => {
+            if host.len() > 255 {
+                return Err(anyhow!("SOCKS5 host name longer than 255 bytes"));
+            }
+            request.push(ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+            port
+        }
+        SocksTarget::Addr(addr) => {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    request.push(ATYP_IPV4);
+                    request.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    request.push(ATYP_IPV6);
+                    request.extend_from_slice(&ip.octets());
+                }
+            }
+            addr.port()
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION || reply[1] != 0 {
+        debug!("SOCKS5 CONNECT rejected with code {}", reply[1]);
+        return Err(anyhow!(ProxyTunnelFailed));
+    }
+    // Skip the bound address, it is of no use for an outgoing connection
+    let address_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(anyhow!(ProxyTunnelFailed))
+    };
+    let mut bound = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use anyhow::Result;
+    use crate::error::MyError;
+    use crate::socks::{connect, SocksTarget};
+
+    /// Minimal SOCKS5 server accepting `user:secret` and echoing the tunnelled data.
+    async fn socks_server() -> Result<(u16, tokio::task::JoinHandle<Result<Vec<u8>>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await?;
+            stream.write_all(&[5, 2]).await?;
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header).await?;
+            let mut user = vec![0u8; header[1] as usize];
+            stream.read_exact(&mut user).await?;
+            let mut password = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut password).await?;
+            let accepted = user == b"user" && password == b"secret";
+            stream.write_all(&[1, if accepted { 0 } else { 1 }]).await?;
+            if !accepted {
+                return Ok(Vec::new());
+            }
+            let mut request = [0u8; 5];
+            stream.read_exact(&mut request).await?;
+            let mut destination = vec![0u8; request[4] as usize + 2];
+            stream.read_exact(&mut destination).await?;
+            stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).await?;
+            let mut payload = [0u8; 4];
+            stream.read_exact(&mut payload).await?;
+            stream.write_all(&payload).await?;
+            Ok(destination)
+        });
+        Ok((port, handle))
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_password() -> Result<()> {
+        let (port, server) = socks_server().await?;
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        let credentials = ("user".to_owned(), "secret".to_owned());
+        connect(&mut stream, SocksTarget::Domain("example.com", 443), Some(&credentials)).await?;
+        stream.write_all(b"ping").await?;
+        let mut echo = [0u8; 4];
+        stream.read_exact(&mut echo).await?;
+        assert_eq!(&echo, b"ping");
+        let destination = server.await??;
+        assert_eq!(&destination[..11], b"example.com");
+        assert_eq!(&destination[11..], &443u16.to_be_bytes());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rejected_password() -> Result<()> {
+        let (port, server) = socks_server().await?;
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        let credentials = ("user".to_owned(), "wrong".to_owned());
+        let error = connect(&mut stream, SocksTarget::Domain("example.com", 80), Some(&credentials))
+            .await
+            .expect_err("Authentication must fail");
+        assert_eq!(error.downcast_ref::<MyError>(), Some(&MyError::ProxyAuthFailed));
+        server.await??;
+        Ok(())
+    }
+}
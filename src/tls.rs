@@ -0,0 +1,386 @@
+use serde::Deserialize;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_native_tls::TlsStream as NativeTlsStream;
+use anyhow::{anyhow, Result};
+
+#[cfg(feature = "rustls")]
+use std::sync::Arc;
+#[cfg(feature = "rustls")]
+use crate::constants::TLS_SESSION_CACHE_SIZE;
+#[cfg(feature = "rustls")]
+use tokio_rustls::client::TlsStream as RustlsStream;
+#[cfg(feature = "rustls")]
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+#[cfg(feature = "rustls")]
+use rustls::pki_types::pem::PemObject;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsBackend {
+    #[default]
+    Native,
+    #[cfg(feature = "rustls")]
+    Rustls
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsVersion {
+    Tls12,
+    Tls13
+}
+
+/// How TLS sessions are (re)established. To handshake for every request,
+/// combine it with `reuse.keep_alive: false`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HandshakeMode {
+    /// `Resume` with rustls, `Full` with native-tls, which can't resume sessions.
+    #[default]
+    Auto,
+    /// Every connection performs a full handshake.
+    Full,
+    /// Reconnects resume a cached session of the same host. Requires the rustls backend.
+    Resume
+}
+
+/// Kind of handshake a connection went through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Handshake {
+    Full,
+    Resumed
+}
+
+/// Client certificate and key presented to servers that require mutual TLS.
+/// Parsed once and shared by every connection that uses it.
+#[derive(Clone)]
+pub struct ClientIdentity {
+    native: native_tls::Identity,
+    #[cfg(feature = "rustls")]
+    rustls: Option<(Vec<CertificateDer<'static>>, Arc<PrivateKeyDer<'static>>)>
+}
+
+impl ClientIdentity {
+    /// PEM encoded certificate chain and PKCS#8 private key.
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> Result<ClientIdentity> {
+        Ok(ClientIdentity {
+            native: native_tls::Identity::from_pkcs8(cert, key)?,
+            #[cfg(feature = "rustls")]
+            rustls: Some((
+                CertificateDer::pem_slice_iter(cert).collect::<Result<Vec<_>, _>>()?,
+                Arc::new(PrivateKeyDer::from_pem_slice(key)?)
+            ))
+        })
+    }
+
+    /// DER encoded PKCS#12 archive. Only the native backend can use it.
+    pub fn from_pkcs12(der: &[u8], password: &str) -> Result<ClientIdentity> {
+        Ok(ClientIdentity {
+            native: native_tls::Identity::from_pkcs12(der, password)?,
+            #[cfg(feature = "rustls")]
+            rustls: None
+        })
+    }
+}
+
+impl Debug for ClientIdentity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ClientIdentity")
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TlsOptions {
+    pub backend: TlsBackend,
+    /// Protocols offered via ALPN, e.g. `http/1.1`.
+    pub alpn: Vec<String>,
+    /// Allowed protocol versions, empty means backend defaults.
+    pub versions: Vec<TlsVersion>,
+    /// Allowed cipher suites by IANA name (rustls only), empty means backend defaults.
+    pub cipher_suites: Vec<String>,
+    pub handshake: HandshakeMode,
+    /// Number of sessions kept for resumption (rustls only, 256 by default), 0 disables resumption.
+    pub session_cache_size: Option<usize>,
+    #[serde(skip)]
+    pub client_identity: Option<ClientIdentity>
+}
+
+/// Backend specific connector, built once per client so that the session
+/// cache is shared by its connections.
+#[derive(Clone)]
+pub(crate) enum TlsConnector {
+    Native(tokio_native_tls::TlsConnector),
+    #[cfg(feature = "rustls")]
+    Rustls(tokio_rustls::TlsConnector)
+}
+
+/// Established TLS session, whichever backend produced it.
+pub(crate) enum TlsStream<T> {
+    Native(NativeTlsStream<T>),
+    #[cfg(feature = "rustls")]
+    Rustls(Box<RustlsStream<T>>)
+}
+
+impl TlsConnector {
+    pub(crate) fn new(options: &TlsOptions) -> Result<TlsConnector> {
+        match options.backend {
+            TlsBackend::Native => TlsConnector::native(options),
+            #[cfg(feature = "rustls")]
+            TlsBackend::Rustls => TlsConnector::rustls(options)
+        }
+    }
+
+    fn native(options: &TlsOptions) -> Result<TlsConnector> {
+        let mut builder = native_tls::TlsConnector::builder();
+        builder
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+        // Settings native-tls can't apply fail here, silently ignoring them would skew results
+        if options.handshake == HandshakeMode::Resume {
+            return Err(anyhow!("Session resumption requires the rustls backend"));
+        }
+        if !options.cipher_suites.is_empty() {
+            return Err(anyhow!("Cipher suites can only be chosen with the rustls backend"));
+        }
+        if options.session_cache_size.is_some() {
+            return Err(anyhow!("The session cache size can only be set with the rustls backend"));
+        }
+        if !options.versions.is_empty() && !options.versions.contains(&TlsVersion::Tls12) {
+            return Err(anyhow!("native-tls can't restrict connections to TLS 1.3"));
+        }
+        if let Some(identity) = &options.client_identity {
+            builder.identity(identity.native.clone());
+        }
+        if !options.alpn.is_empty() {
+            let alpn: Vec<&str> = options.alpn.iter().map(String::as_str).collect();
+            builder.request_alpns(&alpn);
+        }
+        if !options.versions.is_empty() {
+            // native-tls has no TLS 1.3 constant, so only the upper bound can exclude it.
+            // Versions without TLS 1.2 were rejected above.
+            builder.min_protocol_version(Some(native_tls::Protocol::Tlsv12));
+            if !options.versions.contains(&TlsVersion::Tls13) {
+                builder.max_protocol_version(Some(native_tls::Protocol::Tlsv12));
+            }
+        }
+        Ok(TlsConnector::Native(tokio_native_tls::TlsConnector::from(builder.build()?)))
+    }
+
+    #[cfg(feature = "rustls")]
+    fn rustls(options: &TlsOptions) -> Result<TlsConnector> {
+        let mut provider = rustls::crypto::ring::default_provider();
+        if !options.cipher_suites.is_empty() {
+            let allowed = |suite: &rustls::SupportedCipherSuite, name: &str| name.eq_ignore_ascii_case(&format!("{:?}", suite.suite()));
+            if let Some(name) = options.cipher_suites.iter().find(|name| !provider.cipher_suites.iter().any(|suite| allowed(suite, name))) {
+                return Err(anyhow!("Unsupported cipher suite {}", name));
+            }
+            provider.cipher_suites.retain(|suite| options.cipher_suites.iter().any(|name| allowed(suite, name)));
+        }
+        let versions: Vec<&'static rustls::SupportedProtocolVersion> = if options.versions.is_empty() {
+            rustls::DEFAULT_VERSIONS.to_vec()
+        } else {
+            options.versions.iter().map(|version| match version {
+                TlsVersion::Tls12 => &rustls::version::TLS12,
+                TlsVersion::Tls13 => &rustls::version::TLS13
+            }).collect()
+        };
+        let provider = Arc::new(provider);
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&versions)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(danger::AcceptAnyCertificate(provider)));
+        let mut config = match &options.client_identity {
+            Some(ClientIdentity { rustls: Some((chain, key)), .. }) => {
+                builder.with_client_auth_cert(chain.clone(), key.clone_key())?
+            }
+            Some(ClientIdentity { rustls: None, .. }) => {
+                return Err(anyhow!("PKCS#12 client identities are not supported by the rustls backend"));
+            }
+            None => builder.with_no_client_auth()
+        };
+        config.alpn_protocols = options.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        let session_cache_size = options.session_cache_size.unwrap_or(TLS_SESSION_CACHE_SIZE);
+        config.resumption = if options.handshake == HandshakeMode::Full || session_cache_size == 0 {
+            rustls::client::Resumption::disabled()
+        } else {
+            rustls::client::Resumption::in_memory_sessions(session_cache_size)
+        };
+        Ok(TlsConnector::Rustls(tokio_rustls::TlsConnector::from(Arc::new(config))))
+    }
+
+    pub(crate) async fn connect<T>(&self, domain: &str, stream: T) -> Result<TlsStream<T>>
+    where T: AsyncRead + AsyncWrite + Unpin
+    {
+        match self {
+            TlsConnector::Native(connector) => {
+                Ok(TlsStream::Native(connector.connect(domain, stream).await?))
+            }
+            #[cfg(feature = "rustls")]
+            TlsConnector::Rustls(connector) => {
+                let server_name = ServerName::try_from(domain.to_owned())?;
+                Ok(TlsStream::Rustls(Box::new(connector.connect(server_name, stream).await?)))
+            }
+        }
+    }
+}
+
+impl<T> TlsStream<T> {
+    pub(crate) fn handshake(&self) -> Handshake {
+        match self {
+            TlsStream::Native(_) => Handshake::Full,
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(stream) => match stream.get_ref().1.handshake_kind() {
+                Some(rustls::HandshakeKind::Resumed) => Handshake::Resumed,
+                _ => Handshake::Full
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TlsStream::Native(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(stream) => Pin::new(stream).poll_read(cx, buf)
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TlsStream::Native(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(stream) => Pin::new(stream).poll_write(cx, buf)
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TlsStream::Native(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(stream) => Pin::new(stream).poll_flush(cx)
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TlsStream::Native(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(stream) => Pin::new(stream).poll_shutdown(cx)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "rustls")]
+    use std::sync::Arc;
+    #[cfg(feature = "rustls")]
+    use anyhow::Result;
+    #[cfg(feature = "rustls")]
+    use rustls::{client::Resumption, CipherSuite, ProtocolVersion};
+    #[cfg(feature = "rustls")]
+    use crate::tls::TlsBackend;
+    use crate::tls::{HandshakeMode, TlsConnector, TlsOptions, TlsVersion};
+
+    #[test]
+    fn test_native_options() {
+        assert!(TlsConnector::new(&TlsOptions::default()).is_ok());
+        assert!(TlsConnector::new(&TlsOptions { handshake: HandshakeMode::Resume, ..TlsOptions::default() }).is_err());
+        assert!(TlsConnector::new(&TlsOptions { versions: vec![TlsVersion::Tls12], ..TlsOptions::default() }).is_ok());
+        assert!(TlsConnector::new(&TlsOptions { versions: vec![TlsVersion::Tls13], ..TlsOptions::default() }).is_err());
+        assert!(TlsConnector::new(&TlsOptions { cipher_suites: vec![String::from("TLS13_AES_128_GCM_SHA256")], ..TlsOptions::default() }).is_err());
+        assert!(TlsConnector::new(&TlsOptions { session_cache_size: Some(16), ..TlsOptions::default() }).is_err());
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn test_rustls_options() -> Result<()> {
+        let config = |options: TlsOptions| -> Result<Arc<rustls::ClientConfig>> {
+            match TlsConnector::new(&TlsOptions { backend: TlsBackend::Rustls, ..options })? {
+                TlsConnector::Rustls(connector) => Ok(connector.config().clone()),
+                TlsConnector::Native(_) => panic!("Rustls connector expected")
+            }
+        };
+
+        let suites = vec![String::from("tls13_aes_128_gcm_sha256")];
+        let suites: Vec<CipherSuite> = config(TlsOptions { cipher_suites: suites, ..TlsOptions::default() })?
+            .crypto_provider().cipher_suites.iter().map(|suite| suite.suite()).collect();
+        assert_eq!(suites, vec![CipherSuite::TLS13_AES_128_GCM_SHA256]);
+        let suites = vec![String::from("TLS13_AES_128_GCM_SHA256"), String::from("TLS13_NO_SUCH_SUITE")];
+        assert!(config(TlsOptions { cipher_suites: suites, ..TlsOptions::default() }).is_err());
+
+        // Enabled versions are only visible in the debug output of the config
+        let versions = |versions: Vec<TlsVersion>| -> Result<bool> {
+            let expected = format!("versions: {:?}", versions.iter().map(|version| match version {
+                TlsVersion::Tls12 => ProtocolVersion::TLSv1_2,
+                TlsVersion::Tls13 => ProtocolVersion::TLSv1_3
+            }).collect::<Vec<_>>());
+            Ok(format!("{:?}", config(TlsOptions { versions, ..TlsOptions::default() })?).contains(&expected))
+        };
+        assert!(versions(vec![TlsVersion::Tls12])?);
+        assert!(versions(vec![TlsVersion::Tls13])?);
+        assert!(versions(vec![TlsVersion::Tls12, TlsVersion::Tls13])?);
+
+        let disabled = format!("{:?}", Resumption::disabled());
+        assert_ne!(format!("{:?}", config(TlsOptions::default())?.resumption), disabled);
+        assert_eq!(format!("{:?}", config(TlsOptions { handshake: HandshakeMode::Full, ..TlsOptions::default() })?.resumption), disabled);
+        assert_eq!(format!("{:?}", config(TlsOptions { session_cache_size: Some(0), ..TlsOptions::default() })?.resumption), disabled);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rustls")]
+mod danger {
+    use std::sync::Arc;
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+    use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    use rustls::{DigitallySignedStruct, Error, SignatureScheme};
+
+    /// Same policy as the native backend: certificates and host names are not verified.
+    #[derive(Debug)]
+    pub(super) struct AcceptAnyCertificate(pub(super) Arc<CryptoProvider>);
+
+    impl ServerCertVerifier for AcceptAnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime
+        ) -> Result<ServerCertVerified, Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct
+        ) -> Result<HandshakeSignatureValid, Error> {
+            verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct
+        ) -> Result<HandshakeSignatureValid, Error> {
+            verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+}
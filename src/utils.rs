@@ -1,11 +1,18 @@
 use std::collections::HashMap;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
+use url::Url;
 use log::{info};
 use tokio::sync::Mutex;
+use tokio::time::{timeout_at, Instant};
 
 use anyhow::{anyhow, Result};
-use crate::error::MyError::IpResolve;
+use crate::connection::AddressSelection;
+use crate::error::MyError;
+use crate::header::HttpHeader;
 
 pub struct Statistics {
     times: HashMap<&'static str, (u128, u32)>,
@@ -56,12 +63,156 @@ macro_rules! measure_time {
 pub const NEWLINE: &str = "\r\n";
 pub const NEWLINE_BYTES: &[u8] = NEWLINE.as_bytes();
 
-pub(crate) fn ip_resolve(host: &str, port: u16) -> Result<SocketAddr> {
-    let addrs_iter = (host, port).to_socket_addrs()?;
-    for addr in addrs_iter {
-        if addr.is_ipv4() {
-            return Ok(addr);
+static ADDRESS_CURSOR: AtomicUsize = AtomicUsize::new(0);
+static LOCAL_ADDRESS_CURSOR: AtomicUsize = AtomicUsize::new(0);
+static LOCAL_PORT_CURSOR: AtomicUsize = AtomicUsize::new(0);
+
+/// Splits a `unix:/path/to/socket:/uri` URL (the nginx convention) into the
+/// socket path and the request target.
+pub(crate) fn unix_socket_target(url: &Url) -> Option<(&str, &str)> {
+    if url.scheme() != "unix" {
+        return None;
+    }
+    Some(match url.path().split_once(":/") {
+        Some((socket, _)) => (socket, &url.path()[socket.len() + 1..]),
+        None => (url.path(), "/")
+    })
+}
+
+/// Orders resolved addresses for connection attempts: the selection picks the
+/// first address, the rest follow alternating between IPv6 and IPv4 (RFC 8305).
+pub(crate) fn order_addresses(mut addrs: Vec<SocketAddr>, selection: AddressSelection) -> Vec<SocketAddr> {
+    let start = match selection {
+        AddressSelection::First => 0,
+        AddressSelection::RoundRobin => ADDRESS_CURSOR.fetch_add(1, Ordering::Relaxed) % addrs.len(),
+        AddressSelection::Random => fastrand::usize(..addrs.len())
+    };
+    addrs.rotate_left(start);
+    let first_is_v6 = addrs[0].is_ipv6();
+    let (mut same, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_v6);
+    let mut ordered = Vec::with_capacity(same.len() + other.len());
+    same.reverse();
+    other.reverse();
+    while let Some(addr) = same.pop() {
+        ordered.push(addr);
+        if let Some(addr) = other.pop() {
+            ordered.push(addr);
+        }
+    }
+    ordered.extend(other.into_iter().rev());
+    ordered
+}
+
+/// Next source address of the requested family, round-robin over all connections.
+pub(crate) fn next_local_address(addresses: &[IpAddr], ipv6: bool) -> Option<IpAddr> {
+    let candidates: Vec<&IpAddr> = addresses.iter().filter(|ip| ip.is_ipv6() == ipv6).collect();
+    if candidates.is_empty() {
+        return None;
+    }
+    let index = LOCAL_ADDRESS_CURSOR.fetch_add(1, Ordering::Relaxed) % candidates.len();
+    Some(*candidates[index])
+}
+
+/// Next port of the inclusive range, so that consecutive binds don't retry the same busy port.
+pub(crate) fn next_local_port(first: u16, last: u16) -> u16 {
+    let span = last as usize - first as usize + 1;
+    first + (LOCAL_PORT_CURSOR.fetch_add(1, Ordering::Relaxed) % span) as u16
+}
+
+/// Whether the string is a token (RFC 9110, 5.6.2), as method and header names must be.
+pub(crate) fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Whether a comma separated header, such as `Connection`, contains the token.
+pub(crate) fn header_has_token(headers: &[HttpHeader], name: &str, token: &str) -> bool {
+    headers.iter()
+        .filter(|header| header.name.eq_ignore_ascii_case(name))
+        .flat_map(|header| header.value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// The `max` parameter of a `Keep-Alive: timeout=5, max=100` header.
+pub(crate) fn keep_alive_max(headers: &[HttpHeader]) -> Option<usize> {
+    headers.iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("Keep-Alive"))
+        .flat_map(|header| header.value.split(','))
+        .find_map(|parameter| {
+            let (name, value) = parameter.split_once('=')?;
+            if name.trim().eq_ignore_ascii_case("max") {
+                value.trim().parse().ok()
+            } else {
+                None
+            }
+        })
+}
+
+/// Awaits the future until its phase timeout or the request deadline, whichever
+/// comes first, and reports which of the two expired.
+pub(crate) async fn with_deadline<T>(
+    future: impl Future<Output = Result<T>>,
+    timeout: Option<Duration>,
+    timeout_error: MyError,
+    deadline: Option<Instant>
+) -> Result<T> {
+    let phase_deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let (limit, error) = match (phase_deadline, deadline) {
+        (Some(phase), Some(request)) if request < phase => (request, MyError::RequestTimeout),
+        (Some(phase), _) => (phase, timeout_error),
+        (None, Some(request)) => (request, MyError::RequestTimeout),
+        (None, None) => return future.await
+    };
+    match timeout_at(limit, future).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use anyhow::Result;
+    use url::Url;
+    use crate::connection::AddressSelection;
+    use crate::utils::{order_addresses, unix_socket_target};
+
+    #[test]
+    fn test_unix_socket_target() -> Result<()> {
+        let url = Url::parse("unix:/run/app.sock:/api/items?page=2")?;
+        assert_eq!(unix_socket_target(&url), Some(("/run/app.sock", "/api/items")));
+        assert_eq!(url.query(), Some("page=2"));
+        assert_eq!(unix_socket_target(&Url::parse("unix:/run/app.sock")?), Some(("/run/app.sock", "/")));
+        assert_eq!(unix_socket_target(&Url::parse("http://localhost/run/app.sock:/api")?), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_order_addresses() {
+        let addr = |addr: &str| addr.parse::<SocketAddr>().expect(addr);
+        let (a, b, c) = (addr("10.0.0.1:80"), addr("10.0.0.2:80"), addr("10.0.0.3:80"));
+        let (x, y) = (addr("[fd00::1]:80"), addr("[fd00::2]:80"));
+        let addrs = vec![a, b, c, x, y];
+
+        assert_eq!(order_addresses(addrs.clone(), AddressSelection::First), vec![a, x, b, y, c]);
+        assert_eq!(order_addresses(vec![x, y, a], AddressSelection::First), vec![x, a, y]);
+
+        // Each call starts one address further, the families still alternate
+        let first = order_addresses(addrs.clone(), AddressSelection::RoundRobin);
+        let start = addrs.iter().position(|addr| *addr == first[0]).expect("Known address");
+        let mut expected = addrs.clone();
+        expected.rotate_left((start + 1) % addrs.len());
+        let second = order_addresses(addrs.clone(), AddressSelection::RoundRobin);
+        assert_eq!(second[0], expected[0]);
+        for ordered in [first, second] {
+            assert!(ordered.windows(2).take(3).all(|pair| pair[0].is_ipv6() != pair[1].is_ipv6()));
+        }
+
+        for _ in 0..10 {
+            let mut ordered = order_addresses(addrs.clone(), AddressSelection::Random);
+            assert!(ordered.windows(2).take(3).all(|pair| pair[0].is_ipv6() != pair[1].is_ipv6()));
+            ordered.sort();
+            assert_eq!(ordered, addrs);
         }
     }
-    Err(anyhow!(IpResolve))
-}
\ No newline at end of file
+}